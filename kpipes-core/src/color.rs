@@ -1,4 +1,28 @@
+use crate::settings::GridSize;
 use cgmath::Vector3;
+use rand::Rng;
+use std::{
+    fmt::{Display, Formatter},
+    io,
+    io::BufRead,
+};
+
+/// How far the analogous scheme can move around the color wheel between
+/// pipes.
+const ANALOGOUS_HUE_STEP: f32 = 1.0 / 12.0;
+/// The hue used by the monochrome scheme unless another is given.
+pub const DEFAULT_MONOCHROME_HUE: f32 = 210.0 / 360.0;
+/// How many degrees the hue drift gradient moves per segment unless told
+/// otherwise.
+const DEFAULT_HUE_DRIFT: f32 = 3.0;
+/// How many segments the fade gradient takes unless told otherwise.
+const DEFAULT_FADE_LENGTH: u32 = 40;
+/// How bright the bottom of the height gradient is unless told otherwise.
+const DEFAULT_HEIGHT_MIN: f32 = 0.3;
+/// The colors of the built-in palette.
+const DEFAULT_PALETTE: [u32; 8] = [
+    0xe53935, 0x43a047, 0x1e88e5, 0xfdd835, 0x00acc1, 0x8e24aa, 0xfb8c00, 0xeceff1,
+];
 
 pub trait FromHSB {
     fn from_hsb(hue: f32, saturation: f32, brightness: f32) -> Self;
@@ -54,3 +78,441 @@ impl FromHSB for Vector3<f32> {
         }
     }
 }
+
+pub trait ToHSB {
+    fn to_hsb(&self) -> (f32, f32, f32);
+}
+
+impl ToHSB for Vector3<f32> {
+    fn to_hsb(&self) -> (f32, f32, f32) {
+        let max = self.x.max(self.y).max(self.z);
+        let min = self.x.min(self.y).min(self.z);
+        let chroma = max - min;
+
+        let saturation = if max == 0f32 { 0f32 } else { chroma / max };
+        let hue = if chroma == 0f32 {
+            0f32
+        } else if max == self.x {
+            ((self.y - self.z) / chroma).rem_euclid(6f32) / 6f32
+        } else if max == self.y {
+            ((self.z - self.x) / chroma + 2f32) / 6f32
+        } else {
+            ((self.x - self.y) / chroma + 4f32) / 6f32
+        };
+
+        (hue, saturation, max)
+    }
+}
+
+/// How new pipes are colored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ColorScheme {
+    /// Fully random colors.
+    #[default]
+    Random,
+    /// Colors picked at random from a palette.
+    Palette(Palette),
+    /// Random colors, each with a hue close to the previous pipe's.
+    Analogous,
+    /// Random shades of the given hue, from 0 to 1.
+    Monochrome(f32),
+    /// Every color in a palette in turn, starting over after the last one.
+    Cycle(Palette),
+}
+
+impl ColorScheme {
+    /// Gets the color scheme with the given lowercase name, using the built-in
+    /// palette and the default monochrome hue.
+    pub fn from_name(name: &str) -> Option<ColorScheme> {
+        match name {
+            "random" => Some(ColorScheme::Random),
+            "palette" => Some(ColorScheme::Palette(Palette::default())),
+            "analogous" => Some(ColorScheme::Analogous),
+            "monochrome" => Some(ColorScheme::Monochrome(DEFAULT_MONOCHROME_HUE)),
+            "cycle" => Some(ColorScheme::Cycle(Palette::default())),
+            _ => None,
+        }
+    }
+
+    /// Gets the lowercase name of this color scheme.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorScheme::Random => "random",
+            ColorScheme::Palette(_) => "palette",
+            ColorScheme::Analogous => "analogous",
+            ColorScheme::Monochrome(_) => "monochrome",
+            ColorScheme::Cycle(_) => "cycle",
+        }
+    }
+
+    /// Gets the palette this color scheme picks from, if any.
+    pub fn palette(&self) -> Option<&Palette> {
+        match self {
+            ColorScheme::Palette(palette) | ColorScheme::Cycle(palette) => Some(palette),
+            _ => None,
+        }
+    }
+
+    /// Replaces the palette this color scheme picks from, if it uses one.
+    pub fn with_palette(self, palette: Palette) -> ColorScheme {
+        match self {
+            ColorScheme::Palette(_) => ColorScheme::Palette(palette),
+            ColorScheme::Cycle(_) => ColorScheme::Cycle(palette),
+            scheme => scheme,
+        }
+    }
+}
+
+/// Picks the colors of new pipes according to a color scheme, keeping track
+/// of the pipes it has already picked for.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ColorPicker {
+    pub(crate) scheme: ColorScheme,
+    /// How many colors have been picked so far.
+    pub(crate) picked: usize,
+    /// The hue of the last color picked by the analogous scheme.
+    pub(crate) hue: Option<f32>,
+}
+
+impl ColorPicker {
+    pub(crate) fn new(scheme: ColorScheme) -> ColorPicker {
+        ColorPicker {
+            scheme,
+            picked: 0,
+            hue: None,
+        }
+    }
+
+    /// Picks the color of the next pipe.
+    pub(crate) fn pick<R: Rng>(&mut self, rand: &mut R) -> Vector3<f32> {
+        let color = match &self.scheme {
+            ColorScheme::Random => random_color(rand),
+            ColorScheme::Palette(palette) => {
+                palette.colors[rand.gen_range(0..palette.colors.len())]
+            }
+            ColorScheme::Analogous => {
+                let hue = match self.hue {
+                    Some(hue) => (hue + rand.gen_range(-ANALOGOUS_HUE_STEP..=ANALOGOUS_HUE_STEP))
+                        .rem_euclid(1.0),
+                    None => rand.gen(),
+                };
+                self.hue = Some(hue);
+
+                Vector3::from_hsb(
+                    hue,
+                    rand.gen::<f32>().sqrt().sqrt(),
+                    rand.gen::<f32>().sqrt(),
+                )
+            }
+            ColorScheme::Monochrome(hue) => {
+                Vector3::from_hsb(*hue, rand.gen_range(0.3..=1.0), rand.gen_range(0.3..=1.0))
+            }
+            ColorScheme::Cycle(palette) => palette.colors[self.picked % palette.colors.len()],
+        };
+
+        self.picked += 1;
+        color
+    }
+}
+
+/// Generates a fully random color.
+pub(crate) fn random_color<R: Rng>(rand: &mut R) -> Vector3<f32> {
+    Vector3::from_hsb(
+        rand.gen(),
+        rand.gen::<f32>().sqrt().sqrt(),
+        rand.gen::<f32>().sqrt(),
+    )
+}
+
+/// How a pipe's color changes along its length.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum ColorGradient {
+    /// Every segment has the pipe's color.
+    #[default]
+    None,
+    /// The hue moves around the color wheel by the given number of degrees
+    /// with every segment.
+    HueDrift(f32),
+    /// The color fades to `to` over `length` segments, then stays there.
+    Fade { to: Vector3<f32>, length: u32 },
+    /// The color gets brighter with height, starting from the given fraction
+    /// of its brightness at the bottom of the grid.
+    Height(f32),
+}
+
+impl ColorGradient {
+    /// Parses a gradient in the form `none`, `hue-drift[:DEGREES]`,
+    /// `fade:COLOR[:LENGTH]` or `height[:MIN]`.
+    pub fn parse(value: &str) -> Option<ColorGradient> {
+        let mut parts = value.split(':');
+        let name = parts.next()?;
+        let mut parameter = || parts.next();
+
+        let gradient = match name {
+            "none" => ColorGradient::None,
+            "hue-drift" => ColorGradient::HueDrift(match parameter() {
+                Some(degrees) => degrees
+                    .parse()
+                    .ok()
+                    .filter(|degrees: &f32| degrees.is_finite())?,
+                None => DEFAULT_HUE_DRIFT,
+            }),
+            "fade" => ColorGradient::Fade {
+                to: parse_hex_color(parameter()?)?,
+                length: match parameter() {
+                    Some(length) => length.parse().ok().filter(|&length| length > 0)?,
+                    None => DEFAULT_FADE_LENGTH,
+                },
+            },
+            "height" => ColorGradient::Height(match parameter() {
+                Some(min) => min.parse().ok().filter(|min| (0.0..=1.0).contains(min))?,
+                None => DEFAULT_HEIGHT_MIN,
+            }),
+            _ => return None,
+        };
+
+        parts.next().is_none().then_some(gradient)
+    }
+
+    /// Gets the color of a segment `distance` segments along a pipe of the
+    /// given color.
+    pub(crate) fn apply(
+        &self,
+        color: Vector3<f32>,
+        distance: u32,
+        location: Vector3<usize>,
+        grid_size: GridSize,
+    ) -> Vector3<f32> {
+        match *self {
+            ColorGradient::None => color,
+            ColorGradient::HueDrift(degrees) => {
+                let (hue, saturation, brightness) = color.to_hsb();
+                let drift = (degrees * distance as f32 / 360.0).rem_euclid(1.0);
+                Vector3::from_hsb((hue + drift).rem_euclid(1.0), saturation, brightness)
+            }
+            ColorGradient::Fade { to, length } => {
+                let progress = (distance as f32 / length as f32).min(1.0);
+                color + (to - color) * progress
+            }
+            ColorGradient::Height(min) => {
+                let height = if grid_size.height > 1 {
+                    location.y as f32 / (grid_size.height - 1) as f32
+                } else {
+                    1.0
+                };
+                color * (min + (1.0 - min) * height)
+            }
+        }
+    }
+}
+
+impl Display for ColorGradient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ColorGradient::None => write!(f, "none"),
+            ColorGradient::HueDrift(degrees) => write!(f, "hue-drift:{}", degrees),
+            ColorGradient::Fade { to, length } => write!(f, "fade:{}:{}", hex_color(to), length),
+            ColorGradient::Height(min) => write!(f, "height:{}", min),
+        }
+    }
+}
+
+/// A list of colors for pipes to be picked from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    colors: Vec<Vector3<f32>>,
+}
+
+impl Palette {
+    /// Creates a palette from a list of colors, unless the list is empty.
+    pub fn new(colors: Vec<Vector3<f32>>) -> Option<Palette> {
+        if colors.is_empty() {
+            None
+        } else {
+            Some(Palette { colors })
+        }
+    }
+
+    /// Reads a palette file.
+    ///
+    /// Every line of the file starts with a hex color like `#1e88e5`, which
+    /// can be followed by a name for the color. Blank lines are ignored.
+    pub fn read<R: BufRead>(reader: R) -> Result<Palette, PaletteError> {
+        let mut colors = vec![];
+
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let Some(word) = line.split_whitespace().next() else {
+                continue;
+            };
+
+            match parse_hex_color(word) {
+                Some(color) => colors.push(color),
+                None => return Err(PaletteError::InvalidLine(index + 1, line)),
+            }
+        }
+
+        Palette::new(colors).ok_or(PaletteError::Empty)
+    }
+
+    /// Gets the colors in this palette.
+    pub fn colors(&self) -> &[Vector3<f32>] {
+        &self.colors
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            colors: DEFAULT_PALETTE.iter().map(|&rgb| rgb_color(rgb)).collect(),
+        }
+    }
+}
+
+/// Parses a hex color like `#1e88e5`, with or without the `#`.
+pub(crate) fn parse_hex_color(value: &str) -> Option<Vector3<f32>> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    // from_str_radix would also take a leading sign
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    u32::from_str_radix(hex, 16).ok().map(rgb_color)
+}
+
+/// Converts a color packed as `0xRRGGBB` into its components.
+fn rgb_color(rgb: u32) -> Vector3<f32> {
+    Vector3::new(
+        (rgb >> 16) as f32 / 255.0,
+        (rgb >> 8 & 0xff) as f32 / 255.0,
+        (rgb & 0xff) as f32 / 255.0,
+    )
+}
+
+/// Formats a color as a hex color like `#1e88e5`.
+pub(crate) fn hex_color(color: Vector3<f32>) -> String {
+    let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u32;
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        channel(color.x),
+        channel(color.y),
+        channel(color.z)
+    )
+}
+
+/// Error potentially returned when reading a palette.
+#[derive(Debug)]
+pub enum PaletteError {
+    IOError(io::Error),
+    InvalidLine(usize, String),
+    Empty,
+}
+
+impl From<io::Error> for PaletteError {
+    fn from(e: io::Error) -> Self {
+        PaletteError::IOError(e)
+    }
+}
+
+impl Display for PaletteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaletteError::IOError(e) => write!(f, "Error reading palette: {}", e),
+            PaletteError::InvalidLine(line_number, line) => {
+                write!(f, "Invalid palette line {}: {}", line_number, line)
+            }
+            PaletteError::Empty => write!(f, "Palette has no colors"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        color::{parse_hex_color, ColorGradient, ColorPicker, ColorScheme, Palette, PaletteError},
+        settings::GridSize,
+    };
+    use cgmath::{InnerSpace, Vector3};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn test_read_palette() {
+        let file = "#ff0000 brand red
+
+00ff80
+";
+        let palette = Palette::read(file.as_bytes()).unwrap();
+
+        assert_eq!(
+            palette.colors(),
+            &[
+                Vector3::new(1.0, 0.0, 0.0),
+                Vector3::new(0.0, 1.0, 128.0 / 255.0)
+            ]
+        );
+        assert!(matches!(
+            Palette::read("#ff0000\nred\n".as_bytes()),
+            Err(PaletteError::InvalidLine(2, _))
+        ));
+    }
+
+    #[test]
+    fn test_parse_hex_color() {
+        assert_eq!(
+            parse_hex_color("#0080ff"),
+            Some(Vector3::new(0.0, 128.0 / 255.0, 1.0))
+        );
+        assert_eq!(parse_hex_color("ff0000"), Some(Vector3::new(1.0, 0.0, 0.0)));
+
+        for invalid in ["+12345", "#+12345", "#12345", "#12345g", "#1234567"] {
+            assert_eq!(parse_hex_color(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_cycle_picks_palette_in_order() {
+        let palette = Palette::default();
+        let mut colors = ColorPicker::new(ColorScheme::Cycle(palette.clone()));
+        let mut rand = ChaCha8Rng::seed_from_u64(0);
+
+        for index in 0..palette.colors().len() * 2 {
+            assert_eq!(
+                colors.pick(&mut rand),
+                palette.colors()[index % palette.colors().len()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_gradients() {
+        let grid_size = GridSize::new(4, 5, 4);
+        let red = Vector3::new(1.0, 0.0, 0.0);
+        let location = Vector3::new(0, 0, 0);
+
+        let hue_drift = ColorGradient::parse("hue-drift:120").unwrap();
+        let drifted = hue_drift.apply(red, 1, location, grid_size);
+        assert!((drifted - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-5);
+
+        let fade = ColorGradient::parse("fade:#0000ff:4").unwrap();
+        assert_eq!(
+            fade.apply(red, 2, location, grid_size),
+            Vector3::new(0.5, 0.0, 0.5)
+        );
+        assert_eq!(
+            fade.apply(red, 10, location, grid_size),
+            Vector3::new(0.0, 0.0, 1.0)
+        );
+
+        let height = ColorGradient::parse("height:0.5").unwrap();
+        assert_eq!(
+            height.apply(red, 0, location, grid_size),
+            Vector3::new(0.5, 0.0, 0.0)
+        );
+        assert_eq!(height.apply(red, 0, Vector3::new(0, 4, 0), grid_size), red);
+
+        for gradient in [hue_drift, fade, height, ColorGradient::None] {
+            assert_eq!(ColorGradient::parse(&gradient.to_string()), Some(gradient));
+        }
+    }
+}
@@ -1,9 +1,13 @@
-use crate::{SPACE_DEPTH, SPACE_HEIGHT, SPACE_WIDTH};
+use crate::settings::GridSize;
 use cgmath::{One, Vector3};
 use enum_iterator::Sequence;
 use std::ops::{Add, Sub};
 
-/// Describes a direction in 3d space.
+/// Describes a direction in 3d space, towards one of the 26 spaces surrounding
+/// a space.
+///
+/// The first six directions run along the axes, towards the spaces sharing a
+/// face. The rest are diagonal, towards the spaces sharing an edge or a corner.
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Sequence)]
 pub enum Direction {
     Up,
@@ -12,32 +16,169 @@ pub enum Direction {
     West,
     South,
     North,
+    UpEast,
+    UpWest,
+    UpSouth,
+    UpNorth,
+    DownEast,
+    DownWest,
+    DownSouth,
+    DownNorth,
+    SouthEast,
+    SouthWest,
+    NorthEast,
+    NorthWest,
+    UpSouthEast,
+    UpSouthWest,
+    UpNorthEast,
+    UpNorthWest,
+    DownSouthEast,
+    DownSouthWest,
+    DownNorthEast,
+    DownNorthWest,
 }
 
 impl Direction {
-    /// Moves a Vector3 one unit along this direction.
+    /// Gets every direction running along an axis.
+    pub fn axes() -> impl Iterator<Item = Direction> {
+        enum_iterator::all::<Direction>().filter(|direction| !direction.is_diagonal())
+    }
+
+    /// Gets the lowercase name of this direction.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Direction::Up => "up",
+            Direction::Down => "down",
+            Direction::East => "east",
+            Direction::West => "west",
+            Direction::South => "south",
+            Direction::North => "north",
+            Direction::UpEast => "up-east",
+            Direction::UpWest => "up-west",
+            Direction::UpSouth => "up-south",
+            Direction::UpNorth => "up-north",
+            Direction::DownEast => "down-east",
+            Direction::DownWest => "down-west",
+            Direction::DownSouth => "down-south",
+            Direction::DownNorth => "down-north",
+            Direction::SouthEast => "south-east",
+            Direction::SouthWest => "south-west",
+            Direction::NorthEast => "north-east",
+            Direction::NorthWest => "north-west",
+            Direction::UpSouthEast => "up-south-east",
+            Direction::UpSouthWest => "up-south-west",
+            Direction::UpNorthEast => "up-north-east",
+            Direction::UpNorthWest => "up-north-west",
+            Direction::DownSouthEast => "down-south-east",
+            Direction::DownSouthWest => "down-south-west",
+            Direction::DownNorthEast => "down-north-east",
+            Direction::DownNorthWest => "down-north-west",
+        }
+    }
+
+    /// Gets the direction with the given lowercase name.
+    pub fn from_name(name: &str) -> Option<Direction> {
+        enum_iterator::all::<Direction>().find(|direction| direction.name() == name)
+    }
+
+    /// Gets the offset to the neighboring space in this direction.
+    pub fn vector(&self) -> Vector3<i32> {
+        let (x, y, z) = match *self {
+            Direction::Up => (0, 1, 0),
+            Direction::Down => (0, -1, 0),
+            Direction::East => (1, 0, 0),
+            Direction::West => (-1, 0, 0),
+            Direction::South => (0, 0, 1),
+            Direction::North => (0, 0, -1),
+            Direction::UpEast => (1, 1, 0),
+            Direction::UpWest => (-1, 1, 0),
+            Direction::UpSouth => (0, 1, 1),
+            Direction::UpNorth => (0, 1, -1),
+            Direction::DownEast => (1, -1, 0),
+            Direction::DownWest => (-1, -1, 0),
+            Direction::DownSouth => (0, -1, 1),
+            Direction::DownNorth => (0, -1, -1),
+            Direction::SouthEast => (1, 0, 1),
+            Direction::SouthWest => (-1, 0, 1),
+            Direction::NorthEast => (1, 0, -1),
+            Direction::NorthWest => (-1, 0, -1),
+            Direction::UpSouthEast => (1, 1, 1),
+            Direction::UpSouthWest => (-1, 1, 1),
+            Direction::UpNorthEast => (1, 1, -1),
+            Direction::UpNorthWest => (-1, 1, -1),
+            Direction::DownSouthEast => (1, -1, 1),
+            Direction::DownSouthWest => (-1, -1, 1),
+            Direction::DownNorthEast => (1, -1, -1),
+            Direction::DownNorthWest => (-1, -1, -1),
+        };
+
+        Vector3::new(x, y, z)
+    }
+
+    /// Gets whether this direction moves along more than one axis.
+    pub fn is_diagonal(&self) -> bool {
+        *self > Direction::North
+    }
+
+    /// Gets whether this direction points the opposite way to another.
+    pub fn is_opposite(&self, other: Direction) -> bool {
+        self.vector() + other.vector() == Vector3::new(0, 0, 0)
+    }
+
+    /// Moves a Vector3 one unit along each axis this direction moves along.
     pub fn offset<I>(&self, vec: Vector3<I>) -> Vector3<I>
     where
         I: One<Output = I> + Add<Output = I> + Sub<Output = I>,
     {
-        match *self {
-            Direction::Up => Vector3::new(vec.x, vec.y + I::one(), vec.z),
-            Direction::Down => Vector3::new(vec.x, vec.y - I::one(), vec.z),
-            Direction::East => Vector3::new(vec.x + I::one(), vec.y, vec.z),
-            Direction::West => Vector3::new(vec.x - I::one(), vec.y, vec.z),
-            Direction::South => Vector3::new(vec.x, vec.y, vec.z + I::one()),
-            Direction::North => Vector3::new(vec.x, vec.y, vec.z - I::one()),
-        }
+        let vector = self.vector();
+        let step = |value: I, component: i32| match component {
+            1 => value + I::one(),
+            -1 => value - I::one(),
+            _ => value,
+        };
+
+        Vector3::new(
+            step(vec.x, vector.x),
+            step(vec.y, vector.y),
+            step(vec.z, vector.z),
+        )
     }
 
-    pub fn is_offset_legal(&self, vec: Vector3<usize>) -> bool {
-        match *self {
-            Direction::Up => vec.y < SPACE_HEIGHT - 1,
-            Direction::Down => vec.y > 0,
-            Direction::East => vec.x < SPACE_WIDTH - 1,
-            Direction::West => vec.x > 0,
-            Direction::South => vec.z < SPACE_DEPTH - 1,
-            Direction::North => vec.z > 0,
-        }
+    /// Gets the direction pointing the opposite way to this one.
+    pub fn opposite(&self) -> Direction {
+        let vector = -self.vector();
+        enum_iterator::all::<Direction>()
+            .find(|direction| direction.vector() == vector)
+            .unwrap()
+    }
+
+    /// Moves a location one space along this direction, wrapping around to
+    /// the opposite side of a grid of the given size when leaving it.
+    pub fn wrapping_offset(&self, vec: Vector3<usize>, size: GridSize) -> Vector3<usize> {
+        let vector = self.vector();
+        let step = |value: usize, component: i32, length: usize| {
+            (value as i64 + component as i64).rem_euclid(length as i64) as usize
+        };
+
+        Vector3::new(
+            step(vec.x, vector.x, size.width),
+            step(vec.y, vector.y, size.height),
+            step(vec.z, vector.z, size.depth),
+        )
+    }
+
+    /// Gets whether moving a location along this direction keeps it within a
+    /// grid of the given size.
+    pub fn is_offset_legal(&self, vec: Vector3<usize>, size: GridSize) -> bool {
+        let vector = self.vector();
+        let legal = |value: usize, component: i32, length: usize| match component {
+            1 => value < length - 1,
+            -1 => value > 0,
+            _ => true,
+        };
+
+        legal(vec.x, vector.x, size.width)
+            && legal(vec.y, vector.y, size.height)
+            && legal(vec.z, vector.z, size.depth)
     }
 }
@@ -1,52 +1,98 @@
-mod color;
-mod direction;
 mod render;
-mod spaces;
+mod scene;
 
+pub mod color;
+pub mod direction;
 pub mod messages;
+pub mod obstacles;
+pub mod recording;
+pub mod settings;
+pub mod simulation;
+pub mod snapshot;
+pub mod spaces;
 
 use crate::{
-    color::FromHSB,
     direction::Direction,
     messages::{FlowControl, FlowEvent, FrameSize, KeyCode, KeyState, KeyboardEvent},
+    recording::RecordingWriter,
     render::{
-        instance::Instance,
+        instance::{Instance, OrientationTable, CELL_BITS, ORIENTATION_COUNT},
         lighting::{Light, Lighting},
+        uniforms::ClearAnimation,
     },
-    spaces::Spaces,
+    scene::{RetiredId, SceneInstances, MAX_SEGMENT_INSTANCES},
+    settings::{ClearTransition, GridSize, Settings},
+    simulation::{PipeEvent, PipeSimulation, Segment, SegmentKind, GROWTH_DURATION},
+    snapshot::Snapshot,
 };
 use arrayvec::ArrayVec;
-use cgmath::{Matrix4, One, Quaternion, Rad, Rotation3, Vector3};
-use futures::executor::block_on;
-use log::info;
-use rand::{thread_rng, Rng};
+use cgmath::{InnerSpace, Matrix4, One, Quaternion, Rad, Rotation3, Vector3};
+use log::{error, info};
 use render::RenderEngine;
-use std::{f32::consts::PI, io::Cursor, time::Duration};
-use wgpu::{CommandBuffer, Device, Queue, TextureFormat, TextureView};
-
-/// How long to wait before causing a pipe to grow.
-const GROWTH_DURATION: Duration = Duration::from_millis(50);
-/// How many times to try to spawn a new pipe before clearing all the pipes.
-const MAX_START_ATTEMPTS: u32 = 3;
-
-const SPACE_WIDTH: usize = 20;
-const SPACE_HEIGHT: usize = 20;
-const SPACE_DEPTH: usize = 20;
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    io,
+    io::{Cursor, Write},
+    mem,
+    time::Duration,
+};
+use wgpu::{BufferAddress, CommandBuffer, Device, Queue, TextureFormat, TextureView};
+
+/// The camera's orbit radius relative to half the grid's diagonal.
+const CAMERA_RADIUS_FACTOR: f32 = 1.27;
+/// The camera's height relative to half the grid's diagonal.
+const CAMERA_HEIGHT_FACTOR: f32 = 0.866;
+/// How long the board takes to animate away when it is cleared.
+const CLEAR_TRANSITION_DURATION: Duration = Duration::from_secs(2);
+/// How long the animation clock runs before it is restarted, if the board
+/// hasn't been cleared in the meantime.
+const CLOCK_RESTART_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How many instances each instance group starts with room for. Groups grow as
+/// needed, so rare pieces don't take up space for a whole grid's worth.
+const INITIAL_INSTANCE_CAPACITY: BufferAddress = 64;
 
 const SINGLE_OBJ: &[u8] = include_bytes!("kpipe-single.obj");
 const START_OBJ: &[u8] = include_bytes!("kpipe-start.obj");
 const STRAIGHT_OBJ: &[u8] = include_bytes!("kpipe-straight.obj");
 const BENT_OBJ: &[u8] = include_bytes!("kpipe-bent.obj");
 const END_OBJ: &[u8] = include_bytes!("kpipe-end.obj");
+const TEAPOT_OBJ: &[u8] = include_bytes!("kpipe-teapot.obj");
+const BALL_OBJ: &[u8] = include_bytes!("kpipe-ball.obj");
+const KNUCKLE_OBJ: &[u8] = include_bytes!("kpipe-knuckle.obj");
+const KNUCKLE_EDGE_OBJ: &[u8] = include_bytes!("kpipe-knuckle-edge.obj");
+const KNUCKLE_CORNER_OBJ: &[u8] = include_bytes!("kpipe-knuckle-corner.obj");
+const CAP_OBJ: &[u8] = include_bytes!("kpipe-cap.obj");
+const OBSTACLE_OBJ: &[u8] = include_bytes!("kpipe-obstacle.obj");
+
+/// The instance group caps are drawn with where pipes wrap around the edge of
+/// the grid.
+const CAP_GROUP: usize = 10;
+/// The number of instance groups pipe segments are drawn with.
+const PIPE_GROUP_COUNT: usize = 11;
+/// The instance group obstacles are drawn with, after every pipe group.
+const OBSTACLE_GROUP: usize = PIPE_GROUP_COUNT;
+const OBSTACLE_COLOR: Vector3<f32> = Vector3::new(0.55, 0.55, 0.6);
 
 pub struct KPipes {
     renderer: RenderEngine,
-    commands: Vec<CommandBuffer>,
     rot: f32,
-    spaces: Spaces,
-    time_since_growth: Duration,
-    current_color: Vector3<f32>,
-    previous_segment: Option<PreviousSegment>,
+    camera_radius: f32,
+    camera_height: f32,
+    simulation: PipeSimulation,
+    events: Vec<PipeEvent>,
+    scene: SceneInstances,
+    /// Animation clock, restarted whenever the board is cleared, or every so
+    /// often if it never is, to keep it precise as an `f32`.
+    time: Duration,
+    clear_transition: ClearTransition,
+    /// When the clear transition currently playing started, if any.
+    clear_started: Option<Duration>,
+    retire_duration: Duration,
+    /// Retired segments still fading away, along with when they finish, in
+    /// the order they were retired.
+    retiring: VecDeque<(Duration, RetiredId)>,
+    recorder: Option<RecordingWriter<Box<dyn Write>>>,
 }
 
 impl KPipes {
@@ -55,39 +101,116 @@ impl KPipes {
         queue: &Queue,
         window_size: FrameSize,
         color_format: TextureFormat,
+        settings: Settings,
     ) -> KPipes {
-        KPipes {
-            renderer: RenderEngine::new(
-                device,
-                queue,
-                window_size,
-                color_format,
-                Lighting::new(
-                    [
-                        Light::new((-2.0, 3.0, -4.0).into(), 1.0),
-                        Light::new((1.0, 2.0, 3.0).into(), 0.6),
-                    ],
-                    0.2,
-                ),
-                &mut [
-                    Cursor::new(SINGLE_OBJ),
-                    Cursor::new(START_OBJ),
-                    Cursor::new(STRAIGHT_OBJ),
-                    Cursor::new(BENT_OBJ),
-                    Cursor::new(END_OBJ),
+        let simulation = match (&settings.replay, &settings.snapshot) {
+            (Some(recording), _) => PipeSimulation::replay(recording),
+            (None, Some(snapshot)) => PipeSimulation::restore(&snapshot.simulation),
+            (None, None) => PipeSimulation::new(&settings),
+        };
+
+        let grid_size = simulation.grid_size();
+        let half_diagonal = grid_size.diagonal() / 2.0;
+
+        let mut meshes = [
+            Cursor::new(SINGLE_OBJ),
+            Cursor::new(START_OBJ),
+            Cursor::new(STRAIGHT_OBJ),
+            Cursor::new(BENT_OBJ),
+            Cursor::new(END_OBJ),
+            Cursor::new(TEAPOT_OBJ),
+            Cursor::new(BALL_OBJ),
+            Cursor::new(KNUCKLE_OBJ),
+            Cursor::new(KNUCKLE_EDGE_OBJ),
+            Cursor::new(KNUCKLE_CORNER_OBJ),
+            Cursor::new(CAP_OBJ),
+            Cursor::new(OBSTACLE_OBJ),
+        ];
+
+        let mut renderer = RenderEngine::new(
+            device,
+            queue,
+            window_size,
+            color_format,
+            Lighting::new(
+                [
+                    Light::new((-2.0, 3.0, -4.0).into(), 1.0),
+                    Light::new((1.0, 2.0, 3.0).into(), 0.6),
                 ],
-                SPACE_WIDTH as u64 * SPACE_HEIGHT as u64 * SPACE_DEPTH as u64,
-            )
-            .unwrap(),
-            commands: vec![],
+                0.2,
+            ),
+            &orientation_table(),
+            &mut meshes,
+            INITIAL_INSTANCE_CAPACITY,
+        )
+        .unwrap();
+
+        renderer.growth_duration = GROWTH_DURATION.as_secs_f32();
+        renderer.retire_duration = settings.retire_duration.as_secs_f32();
+        renderer.grid_center = grid_size.center_offset();
+
+        // make sure the far side of the grid is never clipped
+        renderer.camera.zfar = renderer
+            .camera
+            .zfar
+            .max((CAMERA_RADIUS_FACTOR + CAMERA_HEIGHT_FACTOR + 1.0) * half_diagonal);
+
+        let mut kpipes = KPipes {
+            renderer,
             rot: 0.0,
-            spaces: Default::default(),
-            time_since_growth: Default::default(),
-            current_color: random_color(),
-            previous_segment: None,
+            camera_radius: half_diagonal * CAMERA_RADIUS_FACTOR,
+            camera_height: half_diagonal * CAMERA_HEIGHT_FACTOR,
+            simulation,
+            events: vec![],
+            scene: SceneInstances::new(PIPE_GROUP_COUNT),
+            time: Default::default(),
+            clear_transition: settings.clear_transition,
+            clear_started: None,
+            retire_duration: settings.retire_duration,
+            retiring: VecDeque::new(),
+            recorder: None,
+        };
+
+        if settings.show_obstacles {
+            kpipes.upload_obstacles();
+        }
+
+        if let Some(snapshot) = &settings.snapshot {
+            if settings.replay.is_none() {
+                kpipes.rot = snapshot.camera_rotation;
+                kpipes.rebuild_scene();
+            }
+        }
+
+        kpipes
+    }
+
+    /// Gets the seed the pipes are generated from, so they can be generated
+    /// again.
+    pub fn seed(&self) -> u64 {
+        self.simulation.seed()
+    }
+
+    /// Captures the current state of the scene, so it can be restored later.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            simulation: self.simulation.snapshot(),
+            camera_rotation: self.rot,
         }
     }
 
+    /// Starts recording the pipes as they grow, so they can be replayed later.
+    ///
+    /// This should be called before the first update, so the recording
+    /// includes every pipe.
+    pub fn record<W: Write + 'static>(&mut self, writer: W) -> io::Result<()> {
+        let writer: Box<dyn Write> = Box::new(writer);
+        self.recorder = Some(RecordingWriter::new(writer, &self.simulation)?);
+        self.simulation.start_recording();
+
+        Ok(())
+    }
+
     pub fn event(&mut self, device: &Device, event: FlowEvent) -> FlowControl {
         match event {
             FlowEvent::CloseRequested => FlowControl::Exit,
@@ -116,7 +239,26 @@ impl KPipes {
                 virtual_keycode: Some(KeyCode::C),
                 ..
             } => {
-                self.clear_pipes();
+                // the board is already on its way out, or is controlled by a
+                // recording
+                if self.clear_started.is_none() && !self.simulation.is_replaying() {
+                    self.simulation.clear_pipes(&mut self.events);
+                    self.apply_events();
+                }
+
+                FlowControl::None
+            }
+            KeyboardEvent {
+                state: KeyState::Pressed,
+                virtual_keycode: Some(KeyCode::R),
+                ..
+            } => {
+                if self.clear_started.is_none() && !self.simulation.is_replaying() {
+                    if let Some(pipe) = self.simulation.oldest_pipe() {
+                        self.simulation.remove_pipe(pipe, &mut self.events);
+                        self.apply_events();
+                    }
+                }
 
                 FlowControl::None
             }
@@ -124,14 +266,35 @@ impl KPipes {
         }
     }
 
-    pub fn update(&mut self, device: &Device, delta: Duration) -> FlowControl {
+    pub fn update(&mut self, delta: Duration) -> FlowControl {
         info!("Update FPS: {}", 1.0 / delta.as_secs_f32());
 
-        // update pipes
-        self.time_since_growth += delta;
-        if self.time_since_growth > GROWTH_DURATION {
-            self.time_since_growth -= GROWTH_DURATION;
-            self.grow(device);
+        // update pipes, holding off on new growth until the board has finished
+        // clearing
+        self.time += delta;
+        self.finish_retiring();
+        match self.clear_started {
+            Some(started) => {
+                if self.time - started >= CLEAR_TRANSITION_DURATION {
+                    self.finish_clear();
+                    self.apply_events();
+                }
+            }
+            None => {
+                self.simulation.update(delta, &mut self.events);
+                self.apply_events();
+            }
+        }
+
+        self.write_recorded();
+
+        // only restart the clock while nothing is animating relative to it
+        if self.time >= CLOCK_RESTART_INTERVAL
+            && self.clear_started.is_none()
+            && self.retiring.is_empty()
+        {
+            self.time = Duration::ZERO;
+            self.rebuild_scene();
         }
 
         // update camera
@@ -141,14 +304,11 @@ impl KPipes {
             self.rot -= PI * 2.0;
         }
 
-        let x = self.rot.sin() * 22.0;
-        let z = self.rot.cos() * 22.0;
+        let x = self.rot.sin() * self.camera_radius;
+        let z = self.rot.cos() * self.camera_radius;
 
-        self.renderer.camera.eye = (x, 15.0, z).into();
-
-        let camera_cb = block_on(self.renderer.update_camera(device)).unwrap();
-
-        self.commands.push(camera_cb);
+        self.renderer.camera.eye = (x, self.camera_height, z).into();
+        self.renderer.time = self.time.as_secs_f32();
 
         FlowControl::None
     }
@@ -156,192 +316,356 @@ impl KPipes {
     pub fn render(
         &mut self,
         device: &Device,
+        queue: &Queue,
         to_submit: &mut Vec<CommandBuffer>,
         view: &TextureView,
         delta: Duration,
     ) {
         info!("Render FPS: {}", 1.0 / delta.as_secs_f32());
 
-        self.commands.push(self.renderer.render(device, view));
-        to_submit.append(&mut self.commands);
-    }
+        // everything that changed since the last frame is written in one go,
+        // ahead of the render commands
+        self.renderer.update_uniforms(device, queue);
+        self.renderer.flush_instances(device, queue).unwrap();
 
-    /// Performs a growth step (either growing the current pipe, starting a new
-    /// one, or clearing the screen).
-    fn grow(&mut self, device: &Device) {
-        if let Some(prev) = self.previous_segment {
-            self.grow_existing(device, prev);
-        } else {
-            self.new_pipe(device);
-        };
+        to_submit.push(self.renderer.render(device, view));
     }
 
-    /// Places a pipe segment connected to an existing pipe, changing existing
-    /// pipe models as needed. Will start a new pipe if the current pipe is
-    /// boxed in.
-    fn grow_existing(&mut self, device: &Device, prev: PreviousSegment) {
-        let mut directions = ArrayVec::<Direction, 6>::new();
-
-        for direction in enum_iterator::all::<Direction>() {
-            if direction.is_offset_legal(prev.location)
-                && !self.spaces.get_vec(direction.offset(prev.location))
-            {
-                directions.push(direction);
-            }
-        }
-
-        if directions.is_empty() {
-            self.new_pipe(device);
-        } else {
-            let mut rand = thread_rng();
-
-            let direction: Direction = directions[rand.gen_range(0..directions.len())];
-            let location = direction.offset(prev.location);
-
-            let prev_cb = match prev.group {
-                0 => {
-                    self.renderer.remove_instances(0, 1).unwrap();
-                    block_on(self.renderer.add_instances(
-                        device,
-                        1,
-                        &[Instance {
-                            color: self.current_color,
-                            model: location_matrix(prev.location)
-                                * starting_direction_matrix(direction),
-                        }],
-                    ))
-                    .unwrap()
+    /// Applies the changes described by simulation events to the renderer.
+    ///
+    /// Events following a board clear are left queued until the clear
+    /// transition finishes.
+    fn apply_events(&mut self) {
+        let grid_size = self.simulation.grid_size();
+
+        let mut events = mem::take(&mut self.events).into_iter();
+        while let Some(event) = events.next() {
+            let time = self.time.as_secs_f32();
+
+            match event {
+                PipeEvent::SegmentPlaced(segment) => {
+                    // only the growing end of a pipe is animated
+                    let growth_start = match segment.kind {
+                        SegmentKind::End(_) => time,
+                        _ => Instance::FULLY_GROWN,
+                    };
+
+                    for (group, instance) in
+                        segment_instances(&segment, grid_size, growth_start, time)
+                    {
+                        self.scene
+                            .insert(&mut self.renderer, segment.location, group, instance);
+                    }
+                }
+                PipeEvent::SegmentRetyped { old, new } => {
+                    let old_instance = self.scene.remove(&mut self.renderer, old.location);
+
+                    for (group, instance) in segment_instances(
+                        &new,
+                        grid_size,
+                        Instance::FULLY_GROWN,
+                        old_instance.birth_time,
+                    ) {
+                        self.scene
+                            .insert(&mut self.renderer, new.location, group, instance);
+                    }
                 }
-                4 => {
-                    self.renderer.remove_instances(4, 1).unwrap();
-                    let (rot_matrix, group) = direction_matrix(prev.direction, direction);
-                    block_on(self.renderer.add_instances(
-                        device,
-                        group,
-                        &[Instance {
-                            color: self.current_color,
-                            model: location_matrix(prev.location) * rot_matrix,
-                        }],
-                    ))
-                    .unwrap()
+                PipeEvent::SegmentRemoved(segment) => {
+                    if self.retire_duration.is_zero() {
+                        self.scene.remove(&mut self.renderer, segment.location);
+                    } else {
+                        let retired = self
+                            .scene
+                            .retire(&mut self.renderer, segment.location, time);
+                        self.retiring
+                            .push_back((self.time + self.retire_duration, retired));
+                    }
                 }
-                _ => unreachable!("Invalid previous group type: {}", prev.group),
-            };
-
-            let endpoint_cb = block_on(self.renderer.add_instances(
-                device,
-                4,
-                &[Instance {
-                    color: self.current_color,
-                    model: location_matrix(location) * starting_direction_matrix(direction),
-                }],
-            ))
-            .unwrap();
-
-            self.commands.push(prev_cb);
-            self.commands.push(endpoint_cb);
-
-            self.spaces.set_vec(location);
-            self.previous_segment = Some(PreviousSegment {
-                direction,
-                location,
-                group: 4,
-            });
+                PipeEvent::BoardCleared => {
+                    if self.clear_transition == ClearTransition::Instant {
+                        self.finish_clear();
+                    } else {
+                        self.clear_started = Some(self.time);
+                        self.renderer.clear = Some(ClearAnimation {
+                            transition: self.clear_transition,
+                            start: time,
+                            duration: CLEAR_TRANSITION_DURATION.as_secs_f32(),
+                        });
+
+                        self.events.extend(events);
+                        break;
+                    }
+                }
+            }
         }
     }
 
-    /// Starts growing a new pipe. Will clear the pipes and start over if a
-    /// suitable location cannot be found.
-    fn new_pipe(&mut self, device: &Device) {
-        let mut attempts = 0;
+    /// Adds an instance for every obstacle. Obstacles never change, so this
+    /// only needs to happen once.
+    fn upload_obstacles(&mut self) {
+        let obstacles: Vec<_> = self
+            .simulation
+            .obstacles()
+            .map(|location| Instance {
+                cell: Instance::pack_cell(location),
+                orientation: Orientation::Identity.index(),
+                color: Instance::pack_color(OBSTACLE_COLOR),
+                growth_start: Instance::FULLY_GROWN,
+                birth_time: Instance::PERMANENT,
+                retire_start: Instance::NOT_RETIRED,
+            })
+            .collect();
+
+        self.renderer.add_instances(OBSTACLE_GROUP, &obstacles);
+    }
 
-        let location = loop {
-            if attempts >= MAX_START_ATTEMPTS {
-                self.clear_pipes();
+    /// Rebuilds every instance from the segments on the board.
+    fn rebuild_scene(&mut self) {
+        let grid_size = self.simulation.grid_size();
+        let instances = self.simulation.segments().flat_map(|segment| {
+            segment_instances(segment, grid_size, Instance::FULLY_GROWN, 0.0)
+                .into_iter()
+                .map(|(group, instance)| (segment.location, group, instance))
+        });
 
-                let location = random_location();
-                if self.spaces.get_vec(location) {
-                    panic!("Encountered occupied space in cleared board!");
-                }
-                break location;
+        self.scene.rebuild(&mut self.renderer, instances);
+    }
+
+    /// Writes out everything the simulation has recorded, stopping recording
+    /// if that fails.
+    fn write_recorded(&mut self) {
+        if let Some(recorder) = &mut self.recorder {
+            let result = self
+                .simulation
+                .take_recorded()
+                .iter()
+                .try_for_each(|entry| recorder.write_entry(entry))
+                .and_then(|_| recorder.flush());
+
+            if let Err(e) = result {
+                error!("Error writing recording, recording stopped: {}", e);
+                self.recorder = None;
+                self.simulation.stop_recording();
             }
+        }
+    }
 
-            let location = random_location();
-            if !self.spaces.get_vec(location) {
-                break location;
+    /// Removes the instances of retired segments that have finished fading
+    /// away.
+    fn finish_retiring(&mut self) {
+        while let Some(&(finished, retired)) = self.retiring.front() {
+            if self.time < finished {
+                break;
             }
 
-            attempts += 1;
+            self.scene.remove_retired(&mut self.renderer, retired);
+            self.retiring.pop_front();
+        }
+    }
+
+    /// Removes every instance from the board, ending any clear transition.
+    fn finish_clear(&mut self) {
+        self.scene.clear(&mut self.renderer);
+        self.retiring.clear();
+        self.renderer.clear = None;
+        self.clear_started = None;
+        self.time = Duration::ZERO;
+    }
+}
+
+/// Converts a segment into the instance groups and instance data it is drawn
+/// with.
+///
+/// Where a pipe wraps around the edge of the grid, the segments on either side
+/// are capped off instead of reaching out of the grid.
+fn segment_instances(
+    segment: &Segment,
+    grid_size: GridSize,
+    growth_start: f32,
+    birth_time: f32,
+) -> ArrayVec<(usize, Instance), MAX_SEGMENT_INSTANCES> {
+    // a move that would leave the grid can only have wrapped around it
+    let entered_wrapping = segment
+        .kind
+        .entry()
+        .filter(|from| !from.opposite().is_offset_legal(segment.location, grid_size));
+    let left_wrapping = segment
+        .kind
+        .exit()
+        .filter(|to| !to.is_offset_legal(segment.location, grid_size));
+
+    let mut models = ArrayVec::<_, MAX_SEGMENT_INSTANCES>::new();
+    if let Some(from) = entered_wrapping {
+        // the pipe starts over on this side, opening toward where it goes next
+        let restart = match segment.kind.exit() {
+            Some(to) => SegmentKind::Start(to),
+            None => SegmentKind::Single,
         };
+        models.push(segment_model(restart));
+        models.push((CAP_GROUP, Orientation::Diagonal(from)));
+    } else {
+        models.push(segment_model(segment.kind));
+    }
+    if let Some(to) = left_wrapping {
+        models.push((CAP_GROUP, Orientation::Diagonal(to.opposite())));
+    }
 
-        self.current_color = random_color();
+    let cell = Instance::pack_cell(segment.location);
+    let color = Instance::pack_color(segment.color);
+    models
+        .into_iter()
+        .map(|(group, orientation)| {
+            (
+                group,
+                Instance {
+                    cell,
+                    orientation: orientation.index(),
+                    color,
+                    growth_start,
+                    birth_time,
+                    retire_start: Instance::NOT_RETIRED,
+                },
+            )
+        })
+        .collect()
+}
 
-        let start_cb = block_on(self.renderer.add_instances(
-            device,
-            0,
-            &[Instance {
-                color: self.current_color,
-                model: location_matrix(location),
-            }],
-        ))
-        .unwrap();
+/// Converts a segment kind into its instance group and orientation.
+fn segment_model(kind: SegmentKind) -> (usize, Orientation) {
+    match kind {
+        // diagonal pieces are stretched to reach their neighbors, and every
+        // diagonal turn is drawn as a knuckle rather than an elbow, since
+        // diagonal pipes can turn at eleven different angles
+        SegmentKind::Start(direction) if direction.is_diagonal() => {
+            (1, Orientation::Diagonal(direction))
+        }
+        SegmentKind::Straight(direction) if direction.is_diagonal() => {
+            (2, Orientation::Diagonal(direction))
+        }
+        SegmentKind::End(direction) if direction.is_diagonal() => {
+            (4, Orientation::Diagonal(direction))
+        }
+        SegmentKind::Bent { from, to }
+        | SegmentKind::Teapot { from, to }
+        | SegmentKind::BallJoint { from, to }
+            if from.is_diagonal() || to.is_diagonal() =>
+        {
+            (knuckle_group(from), Orientation::DiagonalRotation(from))
+        }
+        SegmentKind::Single => (0, Orientation::Identity),
+        SegmentKind::Start(direction) => (1, Orientation::Endpoint(direction)),
+        SegmentKind::Straight(direction) => {
+            let (_, group) = direction_matrix(direction, direction);
+            (
+                group,
+                Orientation::Joint {
+                    from: direction,
+                    to: direction,
+                },
+            )
+        }
+        SegmentKind::Bent { from, to } => {
+            let (_, group) = direction_matrix(from, to);
+            (group, Orientation::Joint { from, to })
+        }
+        SegmentKind::End(direction) => (4, Orientation::Endpoint(direction)),
+        // the teapot is oriented like a bent segment, and so is the ball joint
+        SegmentKind::Teapot { from, to } => (5, Orientation::Joint { from, to }),
+        SegmentKind::BallJoint { from, to } => (6, Orientation::Joint { from, to }),
+    }
+}
 
-        self.commands.push(start_cb);
+/// One of the rotations and scales pieces are drawn with, which the shader
+/// looks up by index in the orientation table.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Orientation {
+    /// Drawn as modeled.
+    Identity,
+    /// An intermediate segment along the axes, rotated by
+    /// [`direction_matrix`].
+    Joint { from: Direction, to: Direction },
+    /// An endpoint segment along an axis, rotated by
+    /// [`starting_direction_matrix`].
+    Endpoint(Direction),
+    /// Rotated and stretched by [`diagonal_matrix`].
+    Diagonal(Direction),
+    /// Rotated by [`diagonal_rotation`].
+    DiagonalRotation(Direction),
+}
 
-        self.spaces.set_vec(location);
-        self.previous_segment = Some(PreviousSegment {
-            direction: Direction::Up,
-            location,
-            group: 0,
-        });
+impl Orientation {
+    /// The number of directions running along an axis.
+    const AXES: u32 = 6;
+    /// The number of directions.
+    const DIRECTIONS: u32 = 26;
+
+    const JOINT_START: u32 = 1;
+    const ENDPOINT_START: u32 = Self::JOINT_START + Self::AXES * Self::AXES;
+    const DIAGONAL_START: u32 = Self::ENDPOINT_START + Self::AXES;
+    const DIAGONAL_ROTATION_START: u32 = Self::DIAGONAL_START + Self::DIRECTIONS;
+    /// The number of orientation indices, including those of impossible
+    /// joints between opposite directions.
+    const COUNT: u32 = Self::DIAGONAL_ROTATION_START + Self::DIRECTIONS;
+
+    /// Gets this orientation's index in the orientation table.
+    fn index(&self) -> u32 {
+        match *self {
+            Orientation::Identity => 0,
+            Orientation::Joint { from, to } => {
+                Self::JOINT_START + from as u32 * Self::AXES + to as u32
+            }
+            Orientation::Endpoint(direction) => Self::ENDPOINT_START + direction as u32,
+            Orientation::Diagonal(direction) => Self::DIAGONAL_START + direction as u32,
+            Orientation::DiagonalRotation(direction) => {
+                Self::DIAGONAL_ROTATION_START + direction as u32
+            }
+        }
     }
 
-    /// Clears all the pipes.
-    fn clear_pipes(&mut self) {
-        self.spaces.clear();
-        self.renderer.clear_instances(0);
-        self.renderer.clear_instances(1);
-        self.renderer.clear_instances(2);
-        self.renderer.clear_instances(3);
-        self.renderer.clear_instances(4);
-        self.previous_segment = None;
+    /// Gets every orientation a piece can be drawn with.
+    fn all() -> impl Iterator<Item = Orientation> {
+        let axes = || Direction::axes();
+        let directions = || enum_iterator::all::<Direction>();
+
+        std::iter::once(Orientation::Identity)
+            .chain(axes().flat_map(move |from| {
+                axes()
+                    .filter(move |&to| !from.is_opposite(to))
+                    .map(move |to| Orientation::Joint { from, to })
+            }))
+            .chain(axes().map(Orientation::Endpoint))
+            .chain(directions().map(Orientation::Diagonal))
+            .chain(directions().map(Orientation::DiagonalRotation))
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-struct PreviousSegment {
-    direction: Direction,
-    location: Vector3<usize>,
-    group: usize,
+    /// Gets the rotation and scale matrix of this orientation.
+    fn matrix(&self) -> Matrix4<f32> {
+        match *self {
+            Orientation::Identity => Matrix4::one(),
+            Orientation::Joint { from, to } => direction_matrix(from, to).0,
+            Orientation::Endpoint(direction) => starting_direction_matrix(direction),
+            Orientation::Diagonal(direction) => diagonal_matrix(direction),
+            Orientation::DiagonalRotation(direction) => diagonal_rotation(direction),
+        }
+    }
 }
 
-/// Generates a random color.
-fn random_color() -> Vector3<f32> {
-    let mut rand = thread_rng();
-    Vector3::from_hsb(
-        rand.gen(),
-        rand.gen::<f32>().sqrt().sqrt(),
-        rand.gen::<f32>().sqrt(),
-    )
-}
+// the shader's table has to have room for every orientation
+const _: () = assert!(Orientation::COUNT as usize == ORIENTATION_COUNT);
+// and every cell of the largest grid has to fit in an instance
+const _: () = assert!(1 << CELL_BITS == GridSize::MAX_LENGTH);
 
-/// Generates a random location within the bounds of the pipe space.
-fn random_location() -> Vector3<usize> {
-    let mut rand = thread_rng();
-    Vector3::new(
-        rand.gen_range(0..SPACE_WIDTH),
-        rand.gen_range(0..SPACE_HEIGHT),
-        rand.gen_range(0..SPACE_DEPTH),
-    )
-}
+/// Builds the table of every orientation's matrix, indexed by
+/// [`Orientation::index`].
+fn orientation_table() -> OrientationTable {
+    let mut table = OrientationTable {
+        matrices: [Matrix4::one(); ORIENTATION_COUNT],
+    };
+    for orientation in Orientation::all() {
+        table.matrices[orientation.index() as usize] = orientation.matrix();
+    }
 
-/// Converts a location vector into a translation matrix.
-fn location_matrix(location: Vector3<usize>) -> Matrix4<f32> {
-    Matrix4::from_translation(Vector3::new(
-        location.x as f32 - 9.5,
-        location.y as f32 - 9.5,
-        location.z as f32 - 9.5,
-    ))
+    table
 }
 
 /// Converts a pair of directions into a rotation matrix and pipe type for
@@ -355,6 +679,7 @@ fn direction_matrix(primary: Direction, secondary: Direction) -> (Matrix4<f32>,
             Direction::West => (Quaternion::from_angle_y(Rad(-PI / 2.0)), 3),
             Direction::South => (Quaternion::one(), 3),
             Direction::North => (Quaternion::from_angle_y(Rad(PI)), 3),
+            diagonal => unreachable!("Diagonal direction {:?}", diagonal),
         },
         Direction::Down => match secondary {
             Direction::Up => unreachable!("Down -> Up"),
@@ -372,6 +697,7 @@ fn direction_matrix(primary: Direction, secondary: Direction) -> (Matrix4<f32>,
                 3,
             ),
             Direction::North => (Quaternion::from_angle_x(Rad(PI)), 3),
+            diagonal => unreachable!("Diagonal direction {:?}", diagonal),
         },
         Direction::East => match secondary {
             Direction::Up => (
@@ -389,6 +715,7 @@ fn direction_matrix(primary: Direction, secondary: Direction) -> (Matrix4<f32>,
                 Quaternion::from_angle_x(Rad(PI)) * Quaternion::from_angle_z(Rad(-PI / 2.0)),
                 3,
             ),
+            diagonal => unreachable!("Diagonal direction {:?}", diagonal),
         },
         Direction::West => match secondary {
             Direction::Up => (
@@ -406,6 +733,7 @@ fn direction_matrix(primary: Direction, secondary: Direction) -> (Matrix4<f32>,
                 Quaternion::from_angle_x(Rad(PI)) * Quaternion::from_angle_z(Rad(PI / 2.0)),
                 3,
             ),
+            diagonal => unreachable!("Diagonal direction {:?}", diagonal),
         },
         Direction::South => match secondary {
             Direction::Up => (
@@ -423,6 +751,7 @@ fn direction_matrix(primary: Direction, secondary: Direction) -> (Matrix4<f32>,
             ),
             Direction::South => (Quaternion::from_angle_x(Rad(PI / 2.0)), 2),
             Direction::North => unreachable!("South -> North"),
+            diagonal => unreachable!("Diagonal direction {:?}", diagonal),
         },
         Direction::North => match secondary {
             Direction::Up => (Quaternion::from_angle_x(Rad(-PI / 2.0)), 3),
@@ -440,7 +769,9 @@ fn direction_matrix(primary: Direction, secondary: Direction) -> (Matrix4<f32>,
             ),
             Direction::South => unreachable!("North -> South"),
             Direction::North => (Quaternion::from_angle_x(Rad(-PI / 2.0)), 2),
+            diagonal => unreachable!("Diagonal direction {:?}", diagonal),
         },
+        diagonal => unreachable!("Diagonal direction {:?}", diagonal),
     };
 
     (Matrix4::from(quat), index)
@@ -455,5 +786,31 @@ fn starting_direction_matrix(direction: Direction) -> Matrix4<f32> {
         Direction::West => Matrix4::from_angle_z(Rad(PI / 2.0)),
         Direction::South => Matrix4::from_angle_x(Rad(PI / 2.0)),
         Direction::North => Matrix4::from_angle_x(Rad(-PI / 2.0)),
+        diagonal => unreachable!("Diagonal direction {:?}", diagonal),
+    }
+}
+
+/// Converts a possibly diagonal direction into a rotation of the +y axis onto
+/// it.
+fn diagonal_rotation(direction: Direction) -> Matrix4<f32> {
+    let vector = direction.vector().cast::<f32>().unwrap().normalize();
+    Matrix4::from(Quaternion::from_arc(Vector3::unit_y(), vector, None))
+}
+
+/// Converts a possibly diagonal direction into a rotation of the +y axis onto
+/// it, stretching pieces along it to reach the neighboring space.
+fn diagonal_matrix(direction: Direction) -> Matrix4<f32> {
+    let length = direction.vector().cast::<f32>().unwrap().magnitude();
+    diagonal_rotation(direction) * Matrix4::from_nonuniform_scale(1.0, length, 1.0)
+}
+
+/// Gets the knuckle instance group for a turn entered moving in the given
+/// direction, which depends on how far away the space it was entered from is.
+fn knuckle_group(from: Direction) -> usize {
+    let vector = from.vector();
+    match vector.x.abs() + vector.y.abs() + vector.z.abs() {
+        1 => 7,
+        2 => 8,
+        _ => 9,
     }
 }
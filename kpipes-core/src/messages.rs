@@ -17,6 +17,7 @@ pub enum KeyState {
 pub enum KeyCode {
     Escape,
     C,
+    R,
     Other,
 }
 
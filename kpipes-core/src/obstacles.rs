@@ -0,0 +1,293 @@
+use crate::settings::GridSize;
+use cgmath::Vector3;
+use std::{
+    fmt::{Display, Formatter},
+    io,
+    io::BufRead,
+};
+
+/// The first line of every obstacle mask file.
+const MAGIC: &str = "kpipes-obstacles 1";
+
+/// Obstacles pipes must grow around.
+#[derive(Debug, Clone, Default)]
+pub enum Obstacles {
+    /// Nothing gets in the way of the pipes.
+    #[default]
+    None,
+    /// A sphere in the middle of the grid.
+    Sphere,
+    /// The walls of a box in the middle of the grid.
+    HollowBox,
+    /// Pillars running from the bottom of the grid to the top.
+    Pillars,
+    /// Obstacles loaded from a mask file. The mask's grid size is used instead
+    /// of the configured one.
+    Mask(ObstacleMask),
+}
+
+impl Obstacles {
+    /// Gets the obstacles with the given lowercase name, if they can be built
+    /// procedurally.
+    pub fn from_name(name: &str) -> Option<Obstacles> {
+        match name {
+            "none" => Some(Obstacles::None),
+            "sphere" => Some(Obstacles::Sphere),
+            "box" => Some(Obstacles::HollowBox),
+            "pillars" => Some(Obstacles::Pillars),
+            _ => None,
+        }
+    }
+
+    /// Builds the mask of these obstacles for a grid of the given size.
+    pub fn build(&self, grid_size: GridSize) -> ObstacleMask {
+        let center = grid_size.center_offset();
+
+        match self {
+            Obstacles::None => ObstacleMask::from_fn(grid_size, |_| false),
+            Obstacles::Sphere => {
+                let radius =
+                    (grid_size.width.min(grid_size.height).min(grid_size.depth) as f32 - 1.0) * 0.3;
+
+                ObstacleMask::from_fn(grid_size, |loc| {
+                    let offset = Vector3::new(loc.x as f32, loc.y as f32, loc.z as f32) - center;
+                    offset.x * offset.x + offset.y * offset.y + offset.z * offset.z
+                        < radius * radius
+                })
+            }
+            Obstacles::HollowBox => {
+                let min = Vector3::new(grid_size.width, grid_size.height, grid_size.depth) / 4;
+                let max = Vector3::new(grid_size.width, grid_size.height, grid_size.depth)
+                    - min
+                    - Vector3::new(1, 1, 1);
+
+                // boxes too small to be hollow are left out
+                if max.x < min.x + 2 || max.y < min.y + 2 || max.z < min.z + 2 {
+                    return ObstacleMask::from_fn(grid_size, |_| false);
+                }
+
+                ObstacleMask::from_fn(grid_size, |loc| {
+                    let inside = (min.x..=max.x).contains(&loc.x)
+                        && (min.y..=max.y).contains(&loc.y)
+                        && (min.z..=max.z).contains(&loc.z);
+                    let on_wall = loc.x == min.x
+                        || loc.x == max.x
+                        || loc.y == min.y
+                        || loc.y == max.y
+                        || loc.z == min.z
+                        || loc.z == max.z;
+
+                    inside && on_wall
+                })
+            }
+            Obstacles::Pillars => {
+                ObstacleMask::from_fn(grid_size, |loc| loc.x % 4 == 2 && loc.z % 4 == 2)
+            }
+            Obstacles::Mask(mask) => mask.clone(),
+        }
+    }
+}
+
+/// Marks which spaces in a grid are blocked by obstacles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObstacleMask {
+    size: GridSize,
+    blocked: Vec<bool>,
+}
+
+impl ObstacleMask {
+    /// Creates a mask blocking every space `blocked` returns true for.
+    pub fn from_fn<F: FnMut(Vector3<usize>) -> bool>(
+        size: GridSize,
+        mut blocked: F,
+    ) -> ObstacleMask {
+        let mut mask = vec![false; size.volume()];
+
+        for z in 0..size.depth {
+            for y in 0..size.height {
+                for x in 0..size.width {
+                    mask[x + y * size.width + z * size.width * size.height] =
+                        blocked(Vector3::new(x, y, z));
+                }
+            }
+        }
+
+        ObstacleMask {
+            size,
+            blocked: mask,
+        }
+    }
+
+    /// Reads a mask file.
+    ///
+    /// After the header, the file describes the grid one horizontal layer at a
+    /// time, from the bottom up. Each layer has a row for every z coordinate,
+    /// with a character for every x coordinate, `#` for a blocked space and `.`
+    /// for a free one. Blank lines are ignored.
+    pub fn read<R: BufRead>(reader: R) -> Result<ObstacleMask, ObstacleMaskError> {
+        let mut lines = reader
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()));
+
+        let (_, line) = lines.next().ok_or(ObstacleMaskError::MissingHeader)?;
+        if line?.trim() != MAGIC {
+            return Err(ObstacleMaskError::MissingHeader);
+        }
+
+        let (index, line) = lines.next().ok_or(ObstacleMaskError::MissingHeader)?;
+        let line = line?;
+        let size = line
+            .trim()
+            .strip_prefix("grid ")
+            .and_then(GridSize::parse)
+            .ok_or_else(|| ObstacleMaskError::InvalidLine(index + 1, line.clone()))?;
+
+        let mut blocked = Vec::with_capacity(size.volume());
+        let mut rows = vec![];
+
+        for (index, line) in lines {
+            let line = line?;
+            let row: Option<Vec<bool>> = line
+                .trim()
+                .chars()
+                .map(|c| match c {
+                    '#' => Some(true),
+                    '.' => Some(false),
+                    _ => None,
+                })
+                .collect();
+
+            match row {
+                Some(row) if row.len() == size.width && rows.len() < size.height * size.depth => {
+                    rows.push(row)
+                }
+                _ => return Err(ObstacleMaskError::InvalidLine(index + 1, line)),
+            }
+        }
+
+        if rows.len() < size.height * size.depth {
+            return Err(ObstacleMaskError::MissingRows);
+        }
+
+        // rows are stored layer by layer, but the mask is stored z-major
+        for z in 0..size.depth {
+            for y in 0..size.height {
+                blocked.extend_from_slice(&rows[y * size.depth + z]);
+            }
+        }
+
+        if blocked.iter().all(|&blocked| blocked) {
+            return Err(ObstacleMaskError::NoFreeSpace);
+        }
+
+        Ok(ObstacleMask { size, blocked })
+    }
+
+    /// Blocks a single space.
+    pub(crate) fn block(&mut self, loc: Vector3<usize>) {
+        self.blocked
+            [loc.x + loc.y * self.size.width + loc.z * self.size.width * self.size.height] = true;
+    }
+
+    /// Gets the dimensions of the grid this mask covers.
+    pub fn size(&self) -> GridSize {
+        self.size
+    }
+
+    /// Gets the locations of every blocked space.
+    pub fn locations(&self) -> impl Iterator<Item = Vector3<usize>> + '_ {
+        let size = self.size;
+
+        self.blocked
+            .iter()
+            .enumerate()
+            .filter(|&(_, &blocked)| blocked)
+            .map(move |(index, _)| {
+                Vector3::new(
+                    index % size.width,
+                    index / size.width % size.height,
+                    index / (size.width * size.height),
+                )
+            })
+    }
+}
+
+/// Error potentially returned when reading an obstacle mask.
+#[derive(Debug)]
+pub enum ObstacleMaskError {
+    IOError(io::Error),
+    MissingHeader,
+    InvalidLine(usize, String),
+    MissingRows,
+    NoFreeSpace,
+}
+
+impl From<io::Error> for ObstacleMaskError {
+    fn from(e: io::Error) -> Self {
+        ObstacleMaskError::IOError(e)
+    }
+}
+
+impl Display for ObstacleMaskError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObstacleMaskError::IOError(e) => write!(f, "Error reading obstacle mask: {}", e),
+            ObstacleMaskError::MissingHeader => write!(f, "Not a KPipes obstacle mask"),
+            ObstacleMaskError::InvalidLine(line_number, line) => {
+                write!(f, "Invalid obstacle mask line {}: {}", line_number, line)
+            }
+            ObstacleMaskError::MissingRows => write!(f, "Obstacle mask is missing rows"),
+            ObstacleMaskError::NoFreeSpace => write!(f, "Obstacle mask blocks every space"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        obstacles::{ObstacleMask, ObstacleMaskError},
+        settings::GridSize,
+    };
+    use cgmath::Vector3;
+
+    #[test]
+    fn test_read_mask_layers() {
+        let file = "kpipes-obstacles 1
+grid 3x2x2
+
+#..
+...
+
+...
+..#
+";
+        let mask = ObstacleMask::read(file.as_bytes()).unwrap();
+
+        assert_eq!(mask.size(), GridSize::new(3, 2, 2));
+        assert_eq!(
+            mask.locations().collect::<Vec<_>>(),
+            vec![Vector3::new(0, 0, 0), Vector3::new(2, 1, 1)]
+        );
+    }
+
+    #[test]
+    fn test_rejects_oversized_mask() {
+        let file = "kpipes-obstacles 1\ngrid 1025x1x1\n";
+
+        assert!(matches!(
+            ObstacleMask::read(file.as_bytes()),
+            Err(ObstacleMaskError::InvalidLine(2, _))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_fully_blocked_mask() {
+        let file = "kpipes-obstacles 1\ngrid 1x1x1\n#\n";
+
+        assert!(matches!(
+            ObstacleMask::read(file.as_bytes()),
+            Err(ObstacleMaskError::NoFreeSpace)
+        ));
+    }
+}
@@ -0,0 +1,430 @@
+use crate::{
+    color::ColorGradient,
+    direction::Direction,
+    obstacles::{ObstacleMask, Obstacles},
+    settings::{GridSize, JointStyle},
+    simulation::{PipeId, PipeSimulation},
+};
+use cgmath::Vector3;
+use std::{
+    fmt::{Display, Formatter},
+    io,
+    io::{BufRead, Write},
+    str::{FromStr, SplitWhitespace},
+    time::Duration,
+};
+
+/// The first line of every recording file.
+const MAGIC: &str = "kpipes-recording 1";
+
+/// Describes a single choice made while growing pipes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RecordedAction {
+    /// A pipe was started at a location.
+    Start {
+        pipe: usize,
+        location: Vector3<usize>,
+        color: Vector3<f32>,
+        joint_style: JointStyle,
+    },
+    /// A pipe grew in a direction, with its turn drawn as a teapot if
+    /// `teapot` is set.
+    Grow {
+        pipe: usize,
+        direction: Direction,
+        teapot: bool,
+    },
+    /// The board was cleared.
+    Clear,
+    /// A pipe was removed from the board.
+    Remove { pipe: PipeId },
+}
+
+/// A recorded action along with when it was taken.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RecordedEntry {
+    /// How long the simulation had been updated for when the action was taken.
+    pub time: Duration,
+    pub action: RecordedAction,
+}
+
+/// A recorded pipe growth session that can be replayed.
+///
+/// Recordings can only be read from files, which makes sure every recorded
+/// action can actually be replayed.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    grid_size: GridSize,
+    pipe_count: usize,
+    seed: u64,
+    wrap: bool,
+    color_gradient: ColorGradient,
+    obstacles: ObstacleMask,
+    entries: Vec<RecordedEntry>,
+}
+
+impl Recording {
+    /// Reads a recording written by a [`RecordingWriter`].
+    pub fn read<R: BufRead>(reader: R) -> Result<Recording, RecordingError> {
+        let mut lines = reader.lines().enumerate();
+        let mut next_line = || -> Result<Option<(usize, String)>, RecordingError> {
+            match lines.next() {
+                Some((index, line)) => Ok(Some((index + 1, line?))),
+                None => Ok(None),
+            }
+        };
+
+        match next_line()? {
+            Some((_, line)) if line.trim() == MAGIC => {}
+            _ => return Err(RecordingError::MissingHeader),
+        }
+
+        let (line_number, line) = next_line()?.ok_or(RecordingError::MissingHeader)?;
+        let grid_size = parse_header(line_number, &line, "grid", GridSize::parse)?;
+        let (line_number, line) = next_line()?.ok_or(RecordingError::MissingHeader)?;
+        let pipe_count = parse_header(line_number, &line, "pipes", |value| {
+            value.parse().ok().filter(|&count| count > 0)
+        })?;
+        let (line_number, line) = next_line()?.ok_or(RecordingError::MissingHeader)?;
+        let seed = parse_header(line_number, &line, "seed", |value| value.parse().ok())?;
+
+        let mut recording = Recording {
+            grid_size,
+            pipe_count,
+            seed,
+            wrap: false,
+            color_gradient: ColorGradient::None,
+            obstacles: Obstacles::None.build(grid_size),
+            entries: vec![],
+        };
+
+        // replay everything as it is read to make sure it can be replayed later,
+        // starting once the wrapping, gradient and obstacles preceding the
+        // entries have been read
+        let mut simulation = None;
+        let mut events = vec![];
+        let mut last_time = Duration::ZERO;
+
+        while let Some((line_number, line)) = next_line()? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if simulation.is_none() && line.trim() == "wrap" {
+                recording.wrap = true;
+                continue;
+            }
+            if simulation.is_none() && line.starts_with("gradient") {
+                recording.color_gradient = line
+                    .strip_prefix("gradient ")
+                    .and_then(|value| ColorGradient::parse(value.trim()))
+                    .ok_or(RecordingError::InvalidLine(line_number, line))?;
+                continue;
+            }
+            if simulation.is_none() && line.starts_with("obstacle") {
+                let location = parse_obstacle(&line)
+                    .filter(|&location| grid_size.contains(location))
+                    .ok_or(RecordingError::InvalidLine(line_number, line))?;
+                recording.obstacles.block(location);
+                continue;
+            }
+            let simulation = simulation.get_or_insert_with(|| PipeSimulation::replay(&recording));
+
+            let entry = parse_entry(&line).ok_or(RecordingError::InvalidLine(line_number, line))?;
+
+            if entry.time < last_time || !simulation.apply_action(entry.action, &mut events) {
+                return Err(RecordingError::IllegalAction(line_number));
+            }
+            last_time = entry.time;
+            events.clear();
+
+            recording.entries.push(entry);
+        }
+
+        Ok(recording)
+    }
+
+    /// Gets the dimensions of the space the recorded pipes grew in.
+    pub fn grid_size(&self) -> GridSize {
+        self.grid_size
+    }
+
+    /// Gets the number of pipes that grew at the same time.
+    pub fn pipe_count(&self) -> usize {
+        self.pipe_count
+    }
+
+    /// Gets the seed the recorded pipes were generated with.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Gets whether the recorded pipes wrapped around the edges of the grid.
+    pub fn wraps(&self) -> bool {
+        self.wrap
+    }
+
+    /// Gets how the recorded pipes' colors changed along their lengths.
+    pub fn color_gradient(&self) -> ColorGradient {
+        self.color_gradient
+    }
+
+    /// Gets the spaces the recorded pipes grew around.
+    pub fn obstacles(&self) -> &ObstacleMask {
+        &self.obstacles
+    }
+
+    /// Gets every recorded action, in the order they were taken.
+    pub fn entries(&self) -> &[RecordedEntry] {
+        &self.entries
+    }
+}
+
+/// Writes recorded actions to a file as they are taken.
+pub struct RecordingWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> RecordingWriter<W> {
+    /// Creates a new recording writer, writing the header describing the
+    /// simulation being recorded.
+    pub fn new(mut writer: W, simulation: &PipeSimulation) -> io::Result<RecordingWriter<W>> {
+        writeln!(writer, "{}", MAGIC)?;
+        writeln!(writer, "grid {}", simulation.grid_size())?;
+        writeln!(writer, "pipes {}", simulation.pipe_count())?;
+        writeln!(writer, "seed {}", simulation.seed())?;
+        if simulation.wraps() {
+            writeln!(writer, "wrap")?;
+        }
+        if simulation.color_gradient() != ColorGradient::None {
+            writeln!(writer, "gradient {}", simulation.color_gradient())?;
+        }
+        for location in simulation.obstacles() {
+            writeln!(
+                writer,
+                "obstacle {} {} {}",
+                location.x, location.y, location.z
+            )?;
+        }
+
+        Ok(RecordingWriter { writer })
+    }
+
+    /// Writes a single recorded action.
+    pub fn write_entry(&mut self, entry: &RecordedEntry) -> io::Result<()> {
+        let time = entry.time.as_micros();
+
+        match entry.action {
+            RecordedAction::Start {
+                pipe,
+                location,
+                color,
+                joint_style,
+            } => writeln!(
+                self.writer,
+                "{} start {} {} {} {} {} {} {} {}",
+                time,
+                pipe,
+                location.x,
+                location.y,
+                location.z,
+                color.x,
+                color.y,
+                color.z,
+                joint_style.name()
+            ),
+            RecordedAction::Grow {
+                pipe,
+                direction,
+                teapot,
+            } => writeln!(
+                self.writer,
+                "{} grow {} {}{}",
+                time,
+                pipe,
+                direction.name(),
+                if teapot { " teapot" } else { "" }
+            ),
+            RecordedAction::Clear => writeln!(self.writer, "{} clear", time),
+            RecordedAction::Remove { pipe } => writeln!(self.writer, "{} remove {}", time, pipe),
+        }
+    }
+
+    /// Flushes everything written so far.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Parses a header line in the form `<name> <value>`.
+fn parse_header<T, F: FnOnce(&str) -> Option<T>>(
+    line_number: usize,
+    line: &str,
+    name: &str,
+    parse: F,
+) -> Result<T, RecordingError> {
+    line.strip_prefix(name)
+        .and_then(|value| value.strip_prefix(' '))
+        .and_then(|value| parse(value.trim()))
+        .ok_or_else(|| RecordingError::InvalidLine(line_number, line.to_string()))
+}
+
+/// Parses an obstacle line in the form `obstacle <x> <y> <z>`.
+pub(crate) fn parse_obstacle(line: &str) -> Option<Vector3<usize>> {
+    let mut words = line.split_whitespace();
+    if words.next()? != "obstacle" {
+        return None;
+    }
+
+    let location = Vector3::new(
+        parse_word(&mut words)?,
+        parse_word(&mut words)?,
+        parse_word(&mut words)?,
+    );
+
+    words.next().is_none().then_some(location)
+}
+
+/// Parses a single recorded action line.
+fn parse_entry(line: &str) -> Option<RecordedEntry> {
+    let mut words = line.split_whitespace();
+    let time = Duration::from_micros(parse_word(&mut words)?);
+
+    let action = match words.next()? {
+        "start" => RecordedAction::Start {
+            pipe: parse_word(&mut words)?,
+            location: Vector3::new(
+                parse_word(&mut words)?,
+                parse_word(&mut words)?,
+                parse_word(&mut words)?,
+            ),
+            color: Vector3::new(
+                parse_word(&mut words)?,
+                parse_word(&mut words)?,
+                parse_word(&mut words)?,
+            ),
+            joint_style: JointStyle::from_name(words.next()?)?,
+        },
+        "grow" => RecordedAction::Grow {
+            pipe: parse_word(&mut words)?,
+            direction: Direction::from_name(words.next()?)?,
+            teapot: match words.next() {
+                Some("teapot") => true,
+                Some(_) => return None,
+                None => false,
+            },
+        },
+        "clear" => RecordedAction::Clear,
+        "remove" => RecordedAction::Remove {
+            pipe: PipeId(parse_word(&mut words)?),
+        },
+        _ => return None,
+    };
+
+    if words.next().is_some() {
+        return None;
+    }
+
+    Some(RecordedEntry { time, action })
+}
+
+/// Parses the next word of a line.
+pub(crate) fn parse_word<T: FromStr>(words: &mut SplitWhitespace) -> Option<T> {
+    words.next()?.parse().ok()
+}
+
+/// Error potentially returned when reading a recording.
+#[derive(Debug)]
+pub enum RecordingError {
+    IOError(io::Error),
+    MissingHeader,
+    InvalidLine(usize, String),
+    IllegalAction(usize),
+}
+
+impl From<io::Error> for RecordingError {
+    fn from(e: io::Error) -> Self {
+        RecordingError::IOError(e)
+    }
+}
+
+impl Display for RecordingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordingError::IOError(e) => write!(f, "Error reading recording: {}", e),
+            RecordingError::MissingHeader => write!(f, "Not a KPipes recording"),
+            RecordingError::InvalidLine(line_number, line) => {
+                write!(f, "Invalid recording line {}: {}", line_number, line)
+            }
+            RecordingError::IllegalAction(line_number) => write!(
+                f,
+                "Recorded action on line {} can't be replayed",
+                line_number
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        recording::{Recording, RecordingError, RecordingWriter},
+        settings::{JointStyle, Settings},
+        simulation::PipeSimulation,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_replay_matches_recorded_session() {
+        let mut simulation = PipeSimulation::new(&Settings {
+            pipe_count: 3,
+            teapot_chance: 0.1,
+            joint_style: JointStyle::Mixed,
+            ..Default::default()
+        });
+        simulation.start_recording();
+
+        let mut file = vec![];
+        let mut writer = RecordingWriter::new(&mut file, &simulation).unwrap();
+        let mut recorded_events = vec![];
+
+        for frame in 0..3000 {
+            simulation.update(Duration::from_millis(frame % 40), &mut recorded_events);
+            if frame == 1000 {
+                simulation.clear_pipes(&mut recorded_events);
+            }
+
+            for entry in simulation.take_recorded() {
+                writer.write_entry(&entry).unwrap();
+            }
+        }
+
+        let recording = Recording::read(&file[..]).unwrap();
+        let mut replay = PipeSimulation::replay(&recording);
+        let mut replayed_events = vec![];
+
+        for frame in 0..3000 {
+            replay.update(Duration::from_millis(frame % 40), &mut replayed_events);
+        }
+
+        assert_eq!(recording.seed(), simulation.seed());
+        assert_eq!(recorded_events, replayed_events);
+    }
+
+    #[test]
+    fn test_rejects_overlapping_pipes() {
+        let file = "kpipes-recording 1
+grid 4x4x4
+pipes 1
+seed 0
+0 start 0 1 1 1 1 0 0 mitred
+50000 grow 0 up
+100000 start 0 1 2 1 1 0 0 mitred
+";
+
+        assert!(matches!(
+            Recording::read(file.as_bytes()),
+            Err(RecordingError::IllegalAction(7))
+        ));
+    }
+}
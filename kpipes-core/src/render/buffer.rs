@@ -1,15 +1,19 @@
+use crate::render::util::least_power_of_2_greater;
 use bytemuck::{cast_slice, Pod};
 use std::{marker::PhantomData, mem::size_of};
-use wgpu::{Buffer, BufferAddress, BufferAsyncError, BufferDescriptor, BufferUsages, CommandBuffer, CommandEncoderDescriptor, Device, Maintain, MapMode};
-use crate::render::util::least_power_of_2_greater;
-
-/// Statically-sized wrapper around a GPU buffer.
+use wgpu::{
+    Buffer, BufferAddress, BufferDescriptor, BufferSize, BufferUsages, CommandBuffer,
+    CommandEncoderDescriptor, Device, Queue,
+};
+
+/// Wrapper around a GPU buffer, which grows as data is written to it.
+///
+/// Writes are queued on the [`Queue`] and take effect when the next commands
+/// are submitted, so they never wait on the GPU.
 pub struct BufferWrapper<D: Encodable + Sized> {
     buffer: Buffer,
-    staging_buffer: Option<Buffer>,
-    capacity: BufferAddress,
-    staging_capacity: BufferAddress,
-    size: BufferAddress,
+    usage: BufferUsages,
+    layout: Layout,
 
     _marker: PhantomData<D>,
 }
@@ -40,12 +44,7 @@ impl<D: Encodable + Sized> BufferWrapper<D> {
         );
         staging_buffer.unmap();
 
-        let buffer = device.create_buffer(&BufferDescriptor {
-            label: Some("wrapped_buffer"),
-            size: buffer_size,
-            usage: usage | BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let buffer = create_buffer::<D>(device, size, usage);
 
         let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
             label: Some("initial_buffer_staging_encoder"),
@@ -56,192 +55,207 @@ impl<D: Encodable + Sized> BufferWrapper<D> {
         (
             BufferWrapper {
                 buffer,
-                staging_buffer: Some(staging_buffer),
-                capacity: size,
-                staging_capacity: size,
-                size,
+                usage,
+                layout: Layout {
+                    capacity: size,
+                    size,
+                },
                 _marker: PhantomData,
             },
             encoder.finish(),
         )
     }
 
-    /// Creates a new buffer wrapper with the given capacity.
+    /// Creates a new buffer wrapper with the given initial capacity.
     pub fn new(device: &Device, capacity: BufferAddress, usage: BufferUsages) -> BufferWrapper<D> {
-        let buffer = device.create_buffer(&BufferDescriptor {
-            label: Some("wrapped_buffer"),
-            size: capacity * BufferWrapper::<D>::data_size(),
-            usage: usage | BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let buffer = create_buffer::<D>(device, capacity, usage);
 
         BufferWrapper {
             buffer,
-            staging_buffer: None,
-            capacity,
-            staging_capacity: 0,
-            size: 0,
+            usage,
+            layout: Layout { capacity, size: 0 },
             _marker: PhantomData,
         }
     }
 
-    /// Gets this buffer's size.
-    pub fn len(&self) -> BufferAddress {
-        self.size
-    }
-
     /// Gets this BufferWrapper's wrapped buffer.
     pub fn buffer(&self) -> &Buffer {
         &self.buffer
     }
 
-    /// Effectively clears the data from this buffer.
-    pub fn clear(&mut self) {
-        self.size = 0;
+    /// Sets this buffer's size, growing it if needed. Elements past its old
+    /// size are left unspecified until written.
+    pub fn resize(&mut self, device: &Device, queue: &Queue, size: BufferAddress) {
+        let growth = self.layout.resize(size);
+        self.grow(device, queue, growth);
     }
 
-    /// Removes a number of instances from the end of this buffer.
-    pub fn remove_last(&mut self, instances: BufferAddress) -> Result<(), BufferRemoveError> {
-        if self.size >= instances {
-            self.size -= instances;
-            Ok(())
-        } else {
-            Err(BufferRemoveError::InsufficientSize)
-        }
+    /// Sets the contents of this buffer.
+    pub fn replace_all(&mut self, device: &Device, queue: &Queue, data: &[D]) {
+        let growth = self.layout.replace_all(data.len() as BufferAddress);
+        self.grow(device, queue, growth);
+        self.queue_write(queue, 0, data);
     }
 
-    /// Sets the contents of this buffer.
-    pub async fn replace_all(
+    /// Writes data to this buffer starting at the given index, which can be
+    /// at most this buffer's size. The buffer grows if the data runs past its
+    /// end.
+    pub fn write(
         &mut self,
         device: &Device,
+        queue: &Queue,
+        offset: BufferAddress,
         data: &[D],
-    ) -> Result<CommandBuffer, BufferWriteError> {
-        let data_len = data.len() as BufferAddress;
+    ) -> Result<(), BufferWriteError> {
+        let growth = self.layout.write(offset, data.len() as BufferAddress)?;
+        self.grow(device, queue, growth);
+        self.queue_write(queue, offset, data);
 
-        if data_len > self.capacity {
-            return Err(BufferWriteError::InsufficientCapacity);
-        }
-
-        self.ensure_staging_capacity(device, data_len);
+        Ok(())
+    }
 
-        let staging_buffer = self.staging_buffer.as_ref().unwrap();
+    /// Queues data to be written to this buffer starting at the given index.
+    fn queue_write(&self, queue: &Queue, offset: BufferAddress, data: &[D]) {
+        let Some((byte_offset, byte_size)) = byte_range(
+            offset,
+            data.len() as BufferAddress,
+            BufferWrapper::<D>::data_size(),
+        ) else {
+            return;
+        };
+
+        let mut view = queue
+            .write_buffer_with(&self.buffer, byte_offset, byte_size)
+            .expect("Invalid buffer write");
+        D::encode_slice(data, &mut view);
+    }
 
-        {
-            let staging_slice = staging_buffer.slice(..);
-            let (tx, rx) = futures_intrusive::channel::shared::oneshot_channel();
-            staging_slice.map_async(MapMode::Write, move |res| {
-                tx.send(res).ok();
+    /// Moves this buffer to a bigger one if its layout grew, copying the
+    /// elements it keeps GPU-side.
+    fn grow(&mut self, device: &Device, queue: &Queue, growth: Option<Growth>) {
+        let Some(Growth { capacity, keep }) = growth else {
+            return;
+        };
+
+        let buffer = create_buffer::<D>(device, capacity, self.usage);
+        if keep > 0 {
+            let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("buffer_growth_encoder"),
             });
-            device.poll(Maintain::Wait);
-            rx.receive().await.unwrap()?;
-            let mut mapping = staging_slice.get_mapped_range_mut();
-            let copy_size = (data_len as usize) * D::size();
-            D::encode_slice(data, &mut mapping[..copy_size]);
+            encoder.copy_buffer_to_buffer(
+                &self.buffer,
+                0,
+                &buffer,
+                0,
+                keep * BufferWrapper::<D>::data_size(),
+            );
+
+            // queued writes happen before the next submitted commands, so the
+            // copy is submitted right away to keep it from overwriting any
+            // writes to the new buffer
+            queue.submit(Some(encoder.finish()));
         }
 
-        staging_buffer.unmap();
-
-        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
-            label: Some("buffer_staging_encoder"),
-        });
-
-        encoder.copy_buffer_to_buffer(
-            &staging_buffer,
-            0,
-            &self.buffer,
-            0,
-            data_len * BufferWrapper::<D>::data_size(),
-        );
-
-        // TODO: should we update size here?
-        // Even though the command to copy the data to the actual buffer has not been
-        // submitted yet?
-        self.size = data_len;
-
-        Ok(encoder.finish())
+        self.buffer = buffer;
     }
+}
 
-    /// Append data to the end of this buffer if there is space remaining.
-    pub async fn append(
-        &mut self,
-        device: &Device,
-        data: &[D],
-    ) -> Result<CommandBuffer, BufferWriteError> {
-        let data_len = data.len() as BufferAddress;
-
-        if self.size + data_len > self.capacity {
-            return Err(BufferWriteError::InsufficientCapacity);
-        }
-
-        self.ensure_staging_capacity(device, data_len);
+/// How many elements a buffer has room for, and how many of them are in use.
+///
+/// Kept apart from the GPU buffer itself, so the bookkeeping for each
+/// operation can be worked out without a device.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Layout {
+    capacity: BufferAddress,
+    size: BufferAddress,
+}
 
-        let staging_buffer = self.staging_buffer.as_ref().unwrap();
+/// A move to a bigger buffer, needed before an operation can go ahead.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Growth {
+    /// How many elements the new buffer has room for.
+    capacity: BufferAddress,
+    /// How many elements from the start of the old buffer are copied over.
+    keep: BufferAddress,
+}
 
-        {
-            let staging_slice = staging_buffer.slice(..);
-            let (tx, rx) = futures_intrusive::channel::shared::oneshot_channel();
-            staging_slice.map_async(MapMode::Write, move |res| {
-                tx.send(res).ok();
-            });
-            device.poll(Maintain::Wait);
-            rx.receive().await.unwrap()?;
-            let mut mapping = staging_slice.get_mapped_range_mut();
-            D::encode_slice(data, mapping.as_mut());
+impl Layout {
+    /// Makes room to write `len` elements starting at `offset`, which can be
+    /// at most the current size.
+    fn write(
+        &mut self,
+        offset: BufferAddress,
+        len: BufferAddress,
+    ) -> Result<Option<Growth>, BufferWriteError> {
+        if offset > self.size {
+            return Err(BufferWriteError::InvalidOffset);
         }
 
-        staging_buffer.unmap();
-
-        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
-            label: Some("buffer_staging_encoder"),
-        });
-
-        encoder.copy_buffer_to_buffer(
-            staging_buffer,
-            0,
-            &self.buffer,
-            self.size * BufferWrapper::<D>::data_size(),
-            data_len * BufferWrapper::<D>::data_size(),
-        );
+        let growth = self.reserve(offset + len, self.size);
+        self.size = self.size.max(offset + len);
+        Ok(growth)
+    }
 
-        // TODO: address potential issues with setting size here.
-        self.size += data_len;
+    /// Sets the size, keeping every element that is still in use.
+    fn resize(&mut self, size: BufferAddress) -> Option<Growth> {
+        let growth = self.reserve(size, self.size.min(size));
+        self.size = size;
+        growth
+    }
 
-        Ok(encoder.finish())
+    /// Makes room to replace every element with `len` new ones.
+    fn replace_all(&mut self, len: BufferAddress) -> Option<Growth> {
+        // nothing needs to be kept, since it is all being replaced
+        let growth = self.reserve(len, 0);
+        self.size = len;
+        growth
     }
 
-    /// Makes sure there is enough space in the staging buffer to handle
-    /// whatever needs the staging buffer.
-    fn ensure_staging_capacity(&mut self, device: &Device, size: BufferAddress) {
-        let size = least_power_of_2_greater(size);
-        if self.staging_buffer.is_none() || self.staging_capacity < size {
-            self.staging_buffer = Some(device.create_buffer(&BufferDescriptor {
-                label: Some("wrapped_staging_buffer"),
-                size: size * BufferWrapper::<D>::data_size(),
-                usage: BufferUsages::MAP_WRITE | BufferUsages::COPY_SRC,
-                mapped_at_creation: false,
-            }));
-            self.staging_capacity = size;
+    /// Makes sure there is room for at least `capacity` elements, moving to a
+    /// buffer with room to grow if there isn't. The first `keep` elements are
+    /// copied to the new buffer.
+    fn reserve(&mut self, capacity: BufferAddress, keep: BufferAddress) -> Option<Growth> {
+        if self.capacity >= capacity {
+            return None;
         }
+
+        self.capacity = least_power_of_2_greater(capacity);
+        Some(Growth {
+            capacity: self.capacity,
+            keep,
+        })
     }
 }
 
-/// Error potentially returned from write operations.
-#[derive(Debug, Copy, Clone)]
-pub enum BufferWriteError {
-    InsufficientCapacity,
-    BufferAsyncError,
+/// Gets the offset and size in bytes of `len` elements of `data_size` bytes
+/// each, starting at the element at `offset`, or `None` if there are none.
+fn byte_range(
+    offset: BufferAddress,
+    len: BufferAddress,
+    data_size: BufferAddress,
+) -> Option<(BufferAddress, BufferSize)> {
+    Some((offset * data_size, BufferSize::new(len * data_size)?))
 }
 
-impl From<BufferAsyncError> for BufferWriteError {
-    fn from(_: BufferAsyncError) -> Self {
-        BufferWriteError::BufferAsyncError
-    }
+/// Creates a buffer that can hold `capacity` elements, and can be written to
+/// and copied from.
+fn create_buffer<D: Encodable>(
+    device: &Device,
+    capacity: BufferAddress,
+    usage: BufferUsages,
+) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: Some("wrapped_buffer"),
+        size: capacity * BufferWrapper::<D>::data_size(),
+        usage: usage | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    })
 }
 
-/// Error potentially returned from remove operations.
+/// Error potentially returned from write operations.
 #[derive(Debug, Copy, Clone)]
-pub enum BufferRemoveError {
-    InsufficientSize,
+pub enum BufferWriteError {
+    InvalidOffset,
 }
 
 /// Trait used to help encode objects to buffers.
@@ -270,6 +284,97 @@ impl<E: Pod + Clone> Encodable for E {
     }
 
     fn encode(&self, write_to: &mut [u8]) {
-        write_to.copy_from_slice(cast_slice(&[self.clone()]));
+        write_to.copy_from_slice(cast_slice(std::slice::from_ref(self)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::render::buffer::{byte_range, BufferWriteError, Growth, Layout};
+
+    #[test]
+    fn test_writes_grow_geometrically() {
+        let mut layout = Layout {
+            capacity: 4,
+            size: 0,
+        };
+        assert_eq!(layout.write(0, 4).unwrap(), None);
+
+        // everything written so far is copied to the bigger buffer
+        assert_eq!(
+            layout.write(4, 1).unwrap(),
+            Some(Growth {
+                capacity: 8,
+                keep: 4
+            })
+        );
+        assert_eq!(layout.write(2, 6).unwrap(), None);
+        assert_eq!(
+            layout.write(5, 20).unwrap(),
+            Some(Growth {
+                capacity: 32,
+                keep: 8
+            })
+        );
+        assert_eq!(
+            layout,
+            Layout {
+                capacity: 32,
+                size: 25
+            }
+        );
+    }
+
+    #[test]
+    fn test_only_elements_in_use_are_kept() {
+        let mut layout = Layout {
+            capacity: 8,
+            size: 6,
+        };
+        assert_eq!(layout.resize(3), None);
+        assert_eq!(
+            layout.resize(20),
+            Some(Growth {
+                capacity: 32,
+                keep: 3
+            })
+        );
+        assert_eq!(
+            layout.replace_all(40),
+            Some(Growth {
+                capacity: 64,
+                keep: 0
+            })
+        );
+        assert_eq!(layout.size, 40);
+    }
+
+    #[test]
+    fn test_writes_cannot_leave_gaps() {
+        let mut layout = Layout {
+            capacity: 8,
+            size: 2,
+        };
+        assert!(matches!(
+            layout.write(3, 1),
+            Err(BufferWriteError::InvalidOffset)
+        ));
+        assert_eq!(
+            layout,
+            Layout {
+                capacity: 8,
+                size: 2
+            }
+        );
+
+        assert_eq!(layout.write(2, 1).unwrap(), None);
+        assert_eq!(layout.size, 3);
+    }
+
+    #[test]
+    fn test_byte_range() {
+        let (offset, size) = byte_range(3, 2, 24).unwrap();
+        assert_eq!((offset, size.get()), (72, 48));
+        assert_eq!(byte_range(3, 0, 24), None);
     }
 }
@@ -1,6 +1,6 @@
 use cgmath::{perspective, Deg, Matrix4, Point3, Vector3};
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
@@ -23,6 +23,6 @@ impl Camera {
         let view = Matrix4::look_at_rh(self.eye, self.target, self.up);
         let project = perspective(Deg(self.fovy), self.aspect, self.znear, self.zfar);
 
-        return OPENGL_TO_WGPU_MATRIX * project * view;
+        OPENGL_TO_WGPU_MATRIX * project * view
     }
 }
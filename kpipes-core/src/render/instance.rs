@@ -4,21 +4,85 @@ use cgmath::{Matrix4, Vector3};
 use std::mem::size_of;
 use wgpu::{BufferAddress, VertexAttribute, VertexBufferLayout, VertexStepMode};
 
-/// Instance data.
+/// How many bits each coordinate of a packed grid cell takes up. This must
+/// match the CELL_BITS const in the shader.
+pub const CELL_BITS: u32 = 10;
+/// The number of rotations and scales instances can be drawn with. This must
+/// match the ORIENTATION_COUNT const in the shader.
+pub const ORIENTATION_COUNT: usize = 95;
+
+/// Instance data, packed to keep uploads small.
+///
+/// Instead of a full model matrix, each instance has the grid cell it is
+/// drawn in and an index into the [`OrientationTable`].
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct Instance {
-    pub color: Vector3<f32>,
-    pub model: Matrix4<f32>,
+    /// The grid cell this instance is drawn in, packed by
+    /// [`pack_cell`](Instance::pack_cell).
+    pub cell: u32,
+    /// The index of this instance's rotation and scale in the orientation
+    /// table.
+    pub orientation: u32,
+    /// This instance's color, packed by [`pack_color`](Instance::pack_color).
+    pub color: [u8; 4],
+    /// The time this instance started growing, in the same units as
+    /// `Uniforms::time`.
+    pub growth_start: f32,
+    /// The time this instance's segment was placed, used to order the clear
+    /// transition.
+    pub birth_time: f32,
+    /// The time this instance's segment was retired and started fading away.
+    pub retire_start: f32,
 }
 
 unsafe impl Pod for Instance {}
 unsafe impl Zeroable for Instance {}
 
 impl Instance {
-    const ATTRS: [VertexAttribute; 5] = wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x4, 2 => Float32x4, 3 => Float32x4, 4 => Float32x4];
+    /// Growth start time for instances that should appear fully grown.
+    pub const FULLY_GROWN: f32 = -1.0e9;
+    /// Birth time for instances that are never cleared.
+    pub const PERMANENT: f32 = -1.0;
+    /// Retire start time for instances that have not been retired.
+    pub const NOT_RETIRED: f32 = 1.0e9;
+
+    const ATTRS: [VertexAttribute; 6] = wgpu::vertex_attr_array![0 => Uint32, 1 => Uint32, 2 => Unorm8x4, 7 => Float32, 8 => Float32, 9 => Float32];
+
+    /// Packs the coordinates of a grid cell, panicking if any of them doesn't
+    /// fit in [`CELL_BITS`] bits.
+    pub fn pack_cell(location: Vector3<usize>) -> u32 {
+        assert!(
+            [location.x, location.y, location.z]
+                .iter()
+                .all(|&coordinate| coordinate < 1 << CELL_BITS),
+            "Cell out of range: {:?}",
+            location
+        );
+
+        location.x as u32
+            | (location.y as u32) << CELL_BITS
+            | (location.z as u32) << (CELL_BITS * 2)
+    }
+
+    /// Packs a color into 8 bits per channel.
+    pub fn pack_color(color: Vector3<f32>) -> [u8; 4] {
+        let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        [channel(color.x), channel(color.y), channel(color.z), 255]
+    }
+}
+
+/// The rotation and scale matrices instances can be drawn with, looked up by
+/// their `orientation`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct OrientationTable {
+    pub matrices: [Matrix4<f32>; ORIENTATION_COUNT],
 }
 
+unsafe impl Pod for OrientationTable {}
+unsafe impl Zeroable for OrientationTable {}
+
 impl VertexData for Instance {
     fn desc<'a>() -> VertexBufferLayout<'a> {
         VertexBufferLayout {
@@ -28,3 +92,23 @@ impl VertexData for Instance {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::render::instance::{Instance, CELL_BITS};
+    use cgmath::Vector3;
+
+    #[test]
+    fn test_pack_cell() {
+        assert_eq!(
+            Instance::pack_cell(Vector3::new(1, 2, 1023)),
+            1 | 2 << CELL_BITS | 1023 << (CELL_BITS * 2)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Cell out of range")]
+    fn test_pack_cell_past_limit() {
+        Instance::pack_cell(Vector3::new(0, 1024, 0));
+    }
+}
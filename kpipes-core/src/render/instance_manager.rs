@@ -1,77 +1,331 @@
 use crate::render::{
-    buffer::{BufferRemoveError, BufferWrapper, BufferWriteError},
+    buffer::{BufferWrapper, BufferWriteError},
     instance::Instance,
-    mesh::{Mesh, MeshLoadError},
+    mesh::MeshRange,
+    util::least_power_of_2_greater,
 };
-use std::io::BufRead;
-use wgpu::{BufferAddress, BufferUsages, CommandBuffer, Device, RenderPass};
+use std::{mem, ops::Range};
+use wgpu::{util::DrawIndexedIndirect, BufferAddress, Device, Queue};
 
-/// Manages a set of instances of a mesh.
+/// The most separate ranges of changed instances tracked at once. Past this,
+/// the closest ranges are merged, writing the instances between them again.
+const MAX_DIRTY_RANGES: usize = 8;
+
+/// Refers to a single instance in an InstanceManager, staying valid until the
+/// instance is removed. Handles of removed instances may be reused.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct InstanceHandle(usize);
+
+/// Manages a set of instances of a mesh, kept in its own region of an
+/// instance buffer shared with other InstanceManagers.
 pub struct InstanceManager {
-    mesh: Mesh,
-    instance_buffer: BufferWrapper<Instance>,
+    instances: InstanceList,
+    /// The index in the instance buffer where this manager's region starts.
+    offset: BufferAddress,
+    /// How many instances fit in this manager's region.
+    capacity: BufferAddress,
 }
 
 impl InstanceManager {
-    /// Creates a new instance manager from the given wavefront obj and with the
-    /// given instance capacity.
-    pub fn from_obj<B: BufRead>(
-        device: &Device,
-        reader: &mut B,
-        instance_capacity: BufferAddress,
-    ) -> Result<(InstanceManager, Vec<CommandBuffer>), InstanceManagerCreationError> {
-        let (mesh, mesh_cb) = Mesh::load(device, reader)?;
+    /// Creates a new instance manager whose region of the instance buffer
+    /// starts at `offset` and holds `capacity` instances.
+    pub fn new(offset: BufferAddress, capacity: BufferAddress) -> InstanceManager {
+        InstanceManager {
+            instances: InstanceList::default(),
+            offset,
+            capacity,
+        }
+    }
 
-        let instance_buffer = BufferWrapper::new(device, instance_capacity, BufferUsages::VERTEX);
+    /// Adds instances to this InstanceManager, returning their handles in the
+    /// same order.
+    ///
+    /// Changes are only queued for the GPU by
+    /// [`flush`](InstanceManager::flush).
+    pub fn add_instances(&mut self, instances: &[Instance]) -> Vec<InstanceHandle> {
+        instances
+            .iter()
+            .map(|&instance| self.instances.add(instance))
+            .collect()
+    }
 
-        Ok((
-            InstanceManager {
-                mesh,
-                instance_buffer,
-            },
-            mesh_cb,
-        ))
+    /// Gets one of this InstanceManager's instances.
+    pub fn instance(&self, handle: InstanceHandle) -> Instance {
+        self.instances.get(handle)
     }
 
-    /// Appends instances to this InstanceManager.
-    pub async fn add_instances(
-        &mut self,
-        device: &Device,
-        instances: &[Instance],
-    ) -> Result<CommandBuffer, BufferWriteError> {
-        Ok(self.instance_buffer.append(device, instances).await?)
+    /// Replaces one of this InstanceManager's instances.
+    pub fn update_instance(&mut self, handle: InstanceHandle, instance: Instance) {
+        self.instances.update(handle, instance);
     }
 
-    /// Removes a number of this InstanceManager's last instances.
-    pub fn remove_instances(&mut self, instances: BufferAddress) -> Result<(), BufferRemoveError> {
-        self.instance_buffer.remove_last(instances)
+    /// Removes one of this InstanceManager's instances, returning it.
+    pub fn remove_instance(&mut self, handle: InstanceHandle) -> Instance {
+        self.instances.remove(handle)
     }
 
-    /// Removes all instances from this InstanceManager.
+    /// Removes all instances from this InstanceManager, invalidating every
+    /// handle.
     pub fn clear_instances(&mut self) {
-        self.instance_buffer.clear();
+        self.instances = InstanceList::default();
     }
 
-    /// Draws all the instances managed by this InstanceManager.
-    pub fn draw<'a>(&'a self, render_pass: &mut RenderPass<'a>) {
-        render_pass.set_vertex_buffer(0, self.instance_buffer.buffer().slice(..));
-        self.mesh.bind(render_pass, 1);
-        render_pass.draw_indexed(
-            0..self.mesh.index_len(),
-            0,
-            0..(self.instance_buffer.len() as u32),
-        );
+    /// Whether this InstanceManager has more instances than fit in its region
+    /// of the instance buffer, so it needs to be moved before flushing.
+    pub fn outgrown(&self) -> bool {
+        self.instances.instances.len() as BufferAddress > self.capacity
+    }
+
+    /// Moves this InstanceManager's region of the instance buffer to start at
+    /// `offset`, growing it to fit every instance. All of the instances are
+    /// written again on the next flush.
+    pub fn relocate(&mut self, offset: BufferAddress) {
+        let len = self.instances.instances.len() as BufferAddress;
+        self.offset = offset;
+        self.capacity = self.capacity.max(least_power_of_2_greater(len));
+        self.instances.mark_all_dirty();
+    }
+
+    /// Gets the index in the instance buffer just past this manager's region.
+    pub fn region_end(&self) -> BufferAddress {
+        self.offset + self.capacity
+    }
+
+    /// Queues every change made since the last flush to be written to this
+    /// manager's region of the instance buffer, only writing the ranges of
+    /// instances that changed.
+    pub fn flush(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        instance_buffer: &mut BufferWrapper<Instance>,
+    ) -> Result<(), BufferWriteError> {
+        for dirty in self.instances.take_dirty() {
+            instance_buffer.write(
+                device,
+                queue,
+                self.offset + dirty.start as BufferAddress,
+                &self.instances.instances[dirty],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Gets the arguments for drawing every instance of this manager with the
+    /// given mesh.
+    pub fn draw_args(&self, mesh: MeshRange) -> DrawIndexedIndirect {
+        mesh.draw_args(self.offset as u32, self.instances.instances.len() as u32)
     }
 }
 
-/// Error potentially returned when creating an InstanceManager.
-#[derive(Debug, Copy, Clone)]
-pub enum InstanceManagerCreationError {
-    MeshLoadError(MeshLoadError),
+/// Keeps instances packed together for drawing while handing out handles that
+/// stay valid as other instances are removed, and tracks which instances need
+/// to be written to the GPU.
+#[derive(Debug, Default)]
+struct InstanceList {
+    instances: Vec<Instance>,
+    /// The handle of each instance, by index.
+    handles: Vec<InstanceHandle>,
+    /// The index of each handle's instance, or `None` for unused handles.
+    indices: Vec<Option<usize>>,
+    free_handles: Vec<InstanceHandle>,
+    /// The ranges of instances changed since the last flush, sorted and
+    /// neither overlapping nor touching.
+    dirty: Vec<Range<usize>>,
+}
+
+impl InstanceList {
+    fn add(&mut self, instance: Instance) -> InstanceHandle {
+        let index = self.instances.len();
+        let handle = self.free_handles.pop().unwrap_or_else(|| {
+            self.indices.push(None);
+            InstanceHandle(self.indices.len() - 1)
+        });
+
+        self.instances.push(instance);
+        self.handles.push(handle);
+        self.indices[handle.0] = Some(index);
+        self.mark_dirty(index);
+
+        handle
+    }
+
+    fn get(&self, handle: InstanceHandle) -> Instance {
+        self.instances[self.index(handle)]
+    }
+
+    fn update(&mut self, handle: InstanceHandle, instance: Instance) {
+        let index = self.index(handle);
+        self.instances[index] = instance;
+        self.mark_dirty(index);
+    }
+
+    fn remove(&mut self, handle: InstanceHandle) -> Instance {
+        let index = self.index(handle);
+        let instance = self.instances.swap_remove(index);
+        self.handles.swap_remove(index);
+        self.indices[handle.0] = None;
+        self.free_handles.push(handle);
+
+        // the last instance was moved into the removed instance's place
+        if let Some(&moved) = self.handles.get(index) {
+            self.indices[moved.0] = Some(index);
+            self.mark_dirty(index);
+        }
+
+        instance
+    }
+
+    /// Takes the ranges of instances changed since this was last called,
+    /// leaving out any that have since been removed.
+    fn take_dirty(&mut self) -> Vec<Range<usize>> {
+        let len = self.instances.len();
+        mem::take(&mut self.dirty)
+            .into_iter()
+            .map(|dirty| dirty.start..dirty.end.min(len))
+            .filter(|dirty| !dirty.is_empty())
+            .collect()
+    }
+
+    fn mark_all_dirty(&mut self) {
+        self.dirty.clear();
+        if !self.instances.is_empty() {
+            self.dirty.push(0..self.instances.len());
+        }
+    }
+
+    fn index(&self, handle: InstanceHandle) -> usize {
+        self.indices[handle.0].unwrap_or_else(|| panic!("Stale instance handle {:?}", handle))
+    }
+
+    fn mark_dirty(&mut self, index: usize) {
+        // the first range that contains or comes after the index
+        let position = self.dirty.partition_point(|dirty| dirty.end < index);
+
+        match self.dirty.get_mut(position) {
+            Some(dirty) if dirty.start <= index + 1 => {
+                dirty.start = dirty.start.min(index);
+                dirty.end = dirty.end.max(index + 1);
+
+                // growing the range can make it touch the next one
+                let end = dirty.end;
+                if let Some(next) = self
+                    .dirty
+                    .get(position + 1)
+                    .filter(|next| next.start <= end)
+                {
+                    self.dirty[position].end = next.end;
+                    self.dirty.remove(position + 1);
+                }
+            }
+            _ => self.dirty.insert(position, index..index + 1),
+        }
+
+        if self.dirty.len() > MAX_DIRTY_RANGES {
+            // merge the two ranges with the smallest gap between them
+            let closest = (0..self.dirty.len() - 1)
+                .min_by_key(|&i| self.dirty[i + 1].start - self.dirty[i].end)
+                .unwrap();
+            self.dirty[closest].end = self.dirty[closest + 1].end;
+            self.dirty.remove(closest + 1);
+        }
+    }
 }
 
-impl From<MeshLoadError> for InstanceManagerCreationError {
-    fn from(e: MeshLoadError) -> Self {
-        InstanceManagerCreationError::MeshLoadError(e)
+#[cfg(test)]
+mod tests {
+    use crate::render::{
+        instance::Instance,
+        instance_manager::{InstanceList, InstanceManager},
+        mesh::MeshRange,
+    };
+
+    fn instance(cell: u32) -> Instance {
+        Instance {
+            cell,
+            orientation: 0,
+            color: [255; 4],
+            growth_start: Instance::FULLY_GROWN,
+            birth_time: 0.0,
+            retire_start: Instance::NOT_RETIRED,
+        }
+    }
+
+    #[test]
+    fn test_handles_survive_removal() {
+        let mut list = InstanceList::default();
+        let first = list.add(instance(0));
+        let second = list.add(instance(1));
+        let third = list.add(instance(2));
+        assert_eq!(list.take_dirty(), vec![0..3]);
+
+        // the last instance should be moved into the removed one's place
+        assert_eq!(list.remove(first).cell, 0);
+        assert_eq!(list.instances[0].cell, 2);
+        assert_eq!(list.get(third).cell, 2);
+        assert_eq!(list.get(second).cell, 1);
+        assert_eq!(list.take_dirty(), vec![0..1]);
+
+        list.update(second, instance(3));
+        assert_eq!(list.get(second).cell, 3);
+        assert_eq!(list.take_dirty(), vec![1..2]);
+
+        // removing the last instance leaves nothing to write
+        list.remove(second);
+        assert_eq!(list.take_dirty(), vec![]);
+        assert_eq!(list.get(third).cell, 2);
+    }
+
+    #[test]
+    fn test_outgrown_regions_move_with_every_instance() {
+        let mut manager = InstanceManager::new(4, 2);
+        manager.add_instances(&[instance(0), instance(1)]);
+        assert!(!manager.outgrown());
+        manager.instances.take_dirty();
+
+        manager.add_instances(&[instance(2)]);
+        assert!(manager.outgrown());
+
+        manager.relocate(10);
+        assert!(!manager.outgrown());
+        assert_eq!(manager.region_end(), 14);
+        assert_eq!(manager.instances.take_dirty(), vec![0..3]);
+
+        let mesh = MeshRange {
+            first_index: 6,
+            index_count: 12,
+            base_vertex: 3,
+        };
+        let args = manager.draw_args(mesh);
+        assert_eq!((args.base_instance, args.instance_count), (10, 3));
+        assert_eq!((args.base_index, args.vertex_count), (6, 12));
+    }
+
+    #[test]
+    fn test_distant_changes_are_written_separately() {
+        let mut list = InstanceList::default();
+        let handles: Vec<_> = (0..30).map(|cell| list.add(instance(cell))).collect();
+        list.take_dirty();
+
+        // removing near the front and adding at the end leaves the middle alone
+        list.remove(handles[1]);
+        list.add(instance(30));
+        assert_eq!(list.take_dirty(), vec![1..2, 29..30]);
+
+        // touching changes are merged
+        list.update(handles[4], instance(4));
+        list.update(handles[6], instance(6));
+        list.update(handles[5], instance(5));
+        assert_eq!(list.take_dirty(), vec![4..7]);
+
+        // too many separate changes merge the closest ranges
+        for index in [0, 3, 6, 9, 12, 15, 18, 20, 23] {
+            list.mark_dirty(index);
+        }
+        assert_eq!(
+            list.take_dirty(),
+            vec![0..1, 3..4, 6..7, 9..10, 12..13, 15..16, 18..21, 23..24]
+        );
     }
 }
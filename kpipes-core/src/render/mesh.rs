@@ -2,80 +2,137 @@ use crate::render::{buffer::BufferWrapper, vertex::Vertex};
 use cgmath::Vector3;
 use std::io::BufRead;
 use tobj::{load_obj_buf, LoadError, LoadOptions};
-use wgpu::{BufferUsages, CommandBuffer, Device, IndexFormat, RenderPass};
+use wgpu::{
+    util::DrawIndexedIndirect, BufferUsages, CommandBuffer, Device, IndexFormat, RenderPass,
+};
 
-/// Describes a single mesh.
-pub struct Mesh {
+/// Describes a set of meshes sharing a single vertex and index buffer, so they
+/// can all be drawn without rebinding either.
+pub struct MeshSet {
     vertex_buffer: BufferWrapper<Vertex>,
     index_buffer: BufferWrapper<u32>,
+    ranges: Vec<MeshRange>,
 }
 
-impl Mesh {
-    /// Loads a mesh from memory containing a wavefront obj format object file.
+/// Where a single mesh is in a MeshSet's buffers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MeshRange {
+    pub first_index: u32,
+    pub index_count: u32,
+    pub base_vertex: i32,
+}
+
+impl MeshSet {
+    /// Loads a mesh from each of the given readers of memory containing a
+    /// wavefront obj format object file, in the same order.
     pub fn load<B: BufRead>(
         device: &Device,
-        reader: &mut B,
-    ) -> Result<(Mesh, Vec<CommandBuffer>), MeshLoadError> {
-        let (obj, _) = load_obj_buf(
-            reader,
-            &LoadOptions {
-                single_index: true,
-                triangulate: true,
-                ignore_points: true,
-                ignore_lines: true,
-            },
-            |_p| Err(LoadError::GenericFailure),
-        )?;
-
-        let model = obj
-            .into_iter()
-            .next()
-            .ok_or(MeshLoadError::MissingModelError)?;
-
+        readers: &mut [B],
+    ) -> Result<(MeshSet, Vec<CommandBuffer>), MeshLoadError> {
         let mut vertices = vec![];
+        let mut indices = vec![];
+        let mut ranges = vec![];
+
+        for reader in readers {
+            let (mesh_vertices, mesh_indices) = load_obj(reader)?;
 
-        for i in 0..(model.mesh.positions.len() / 3) {
-            vertices.push(Vertex {
-                position: Vector3::new(
-                    model.mesh.positions[i * 3],
-                    model.mesh.positions[i * 3 + 1],
-                    model.mesh.positions[i * 3 + 2],
-                ),
-                normal: Vector3::new(
-                    model.mesh.normals[i * 3],
-                    model.mesh.normals[i * 3 + 1],
-                    model.mesh.normals[i * 3 + 2],
-                ),
-            })
+            ranges.push(MeshRange {
+                first_index: indices.len() as u32,
+                index_count: mesh_indices.len() as u32,
+                base_vertex: vertices.len() as i32,
+            });
+            vertices.extend(mesh_vertices);
+            indices.extend(mesh_indices);
         }
 
         let (vertex_buffer, vertex_cb) =
             BufferWrapper::from_data(device, &vertices, BufferUsages::VERTEX);
         let (index_buffer, index_cb) =
-            BufferWrapper::from_data(device, &model.mesh.indices, BufferUsages::INDEX);
+            BufferWrapper::from_data(device, &indices, BufferUsages::INDEX);
 
         Ok((
-            Mesh {
+            MeshSet {
                 vertex_buffer,
                 index_buffer,
+                ranges,
             },
             vec![vertex_cb, index_cb],
         ))
     }
 
-    pub fn index_len(&self) -> u32 {
-        self.index_buffer.len() as u32
+    /// Gets where one of this set's meshes is in its buffers.
+    pub fn range(&self, mesh_index: usize) -> MeshRange {
+        self.ranges[mesh_index]
+    }
+
+    /// Gets the number of meshes in this set.
+    pub fn mesh_count(&self) -> usize {
+        self.ranges.len()
     }
 
-    /// Bind this model for a subsequent draw call.
+    /// Bind every mesh in this set for subsequent draw calls.
     pub fn bind<'a>(&'a self, render_pass: &mut RenderPass<'a>, vertex_slot: u32) {
         render_pass.set_vertex_buffer(vertex_slot, self.vertex_buffer.buffer().slice(..));
         render_pass.set_index_buffer(self.index_buffer.buffer().slice(..), IndexFormat::Uint32);
     }
 }
 
+impl MeshRange {
+    /// Gets the arguments for an indexed draw of this mesh, drawing
+    /// `instance_count` instances starting at `first_instance`.
+    pub fn draw_args(&self, first_instance: u32, instance_count: u32) -> DrawIndexedIndirect {
+        DrawIndexedIndirect {
+            vertex_count: self.index_count,
+            instance_count,
+            base_index: self.first_index,
+            vertex_offset: self.base_vertex,
+            base_instance: first_instance,
+        }
+    }
+}
+
+/// Loads the vertices and indices of the first model in a wavefront obj format
+/// object file.
+fn load_obj<B: BufRead>(reader: &mut B) -> Result<(Vec<Vertex>, Vec<u32>), MeshLoadError> {
+    let (obj, _) = load_obj_buf(
+        reader,
+        &LoadOptions {
+            single_index: true,
+            triangulate: true,
+            ignore_points: true,
+            ignore_lines: true,
+        },
+        |_p| Err(LoadError::GenericFailure),
+    )?;
+
+    let model = obj
+        .into_iter()
+        .next()
+        .ok_or(MeshLoadError::MissingModelError)?;
+
+    let mut vertices = vec![];
+
+    for i in 0..(model.mesh.positions.len() / 3) {
+        vertices.push(Vertex {
+            position: Vector3::new(
+                model.mesh.positions[i * 3],
+                model.mesh.positions[i * 3 + 1],
+                model.mesh.positions[i * 3 + 2],
+            ),
+            normal: Vector3::new(
+                model.mesh.normals[i * 3],
+                model.mesh.normals[i * 3 + 1],
+                model.mesh.normals[i * 3 + 2],
+            ),
+        })
+    }
+
+    Ok((vertices, model.mesh.indices))
+}
+
 /// Error potentially returned when loading a mesh.
 #[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
 pub enum MeshLoadError {
     ObjLoadError(LoadError),
     MissingModelError,
@@ -86,3 +143,43 @@ impl From<LoadError> for MeshLoadError {
         MeshLoadError::ObjLoadError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::render::mesh::load_obj;
+
+    // This must match the GROWTH_TO const in the shader.
+    const GROWTH_TO: f32 = 0.25;
+
+    #[test]
+    fn test_pipe_meshes_end_at_growth_front() {
+        // growing pieces are clipped at the growth front, so any part of a pipe
+        // reaching past it would pop in once the pipe finishes growing
+        let pipes: [&[u8]; 5] = [
+            include_bytes!("../kpipe-single.obj"),
+            include_bytes!("../kpipe-start.obj"),
+            include_bytes!("../kpipe-straight.obj"),
+            include_bytes!("../kpipe-bent.obj"),
+            include_bytes!("../kpipe-end.obj"),
+        ];
+
+        for mut obj in pipes {
+            let (vertices, _) = load_obj(&mut obj).unwrap();
+            assert!(vertices.iter().all(|vertex| vertex.position.y <= GROWTH_TO));
+        }
+    }
+
+    #[test]
+    fn test_obstacle_mesh_fills_its_space() {
+        // obstacles reach past the growth front, so they must never be clipped
+        let (vertices, _) = load_obj(&mut &include_bytes!("../kpipe-obstacle.obj")[..]).unwrap();
+        let (min, max) = vertices
+            .iter()
+            .fold((0.0f32, 0.0f32), |(min, max), vertex| {
+                (min.min(vertex.position.y), max.max(vertex.position.y))
+            });
+
+        assert_eq!((min, max), (-0.5, 0.5));
+        assert!(max > GROWTH_TO);
+    }
+}
@@ -12,44 +12,65 @@ pub mod vertex;
 use crate::{
     messages::FrameSize,
     render::{
-        buffer::{BufferRemoveError, BufferWrapper, BufferWriteError},
+        buffer::{BufferWrapper, BufferWriteError},
         camera::Camera,
-        instance::Instance,
-        instance_manager::{InstanceManager, InstanceManagerCreationError},
+        instance::{Instance, OrientationTable},
+        instance_manager::{InstanceHandle, InstanceManager},
         lighting::Lighting,
+        mesh::{MeshLoadError, MeshSet},
         texture::TextureWrapper,
-        uniforms::Uniforms,
+        uniforms::{ClearAnimation, Uniforms},
         vertex::Vertex,
     },
 };
-use std::{borrow::Cow, io, io::BufRead};
+use cgmath::Vector3;
+use std::{borrow::Cow, io, io::BufRead, mem::size_of};
 use wgpu::{
+    util::{BufferInitDescriptor, DeviceExt, DrawIndexedIndirect},
     BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayoutDescriptor,
-    BindGroupLayoutEntry, BindingResource, BindingType, BlendState, BufferAddress,
+    BindGroupLayoutEntry, BindingResource, BindingType, BlendState, Buffer, BufferAddress,
     BufferBindingType, BufferUsages, Color, ColorTargetState, ColorWrites, CommandBuffer,
-    CommandEncoderDescriptor, CompareFunction, DepthStencilState, Device, Face, FragmentState,
-    FrontFace, LoadOp, Operations, PipelineLayoutDescriptor, PolygonMode, PrimitiveState,
-    PrimitiveTopology, Queue, RenderPassColorAttachment, RenderPassDepthStencilAttachment,
-    RenderPassDescriptor, RenderPipeline, RenderPipelineDescriptor, ShaderModuleDescriptor,
-    ShaderSource, ShaderStages, TextureFormat, TextureView, VertexBufferLayout, VertexState,
+    CommandEncoderDescriptor, CompareFunction, DepthStencilState, Device, Face, Features,
+    FragmentState, FrontFace, LoadOp, Operations, PipelineLayoutDescriptor, PolygonMode,
+    PrimitiveState, PrimitiveTopology, Queue, RenderPassColorAttachment,
+    RenderPassDepthStencilAttachment, RenderPassDescriptor, RenderPipeline,
+    RenderPipelineDescriptor, ShaderModuleDescriptor, ShaderSource, ShaderStages, TextureFormat,
+    TextureView, VertexBufferLayout, VertexState,
 };
 
 const SHADER_SRC: &str = include_str!("shader.wgsl");
 
 /// Used to manage the details of how render operations are performed.
 pub struct RenderEngine {
+    meshes: MeshSet,
     instance_groups: Vec<InstanceManager>,
+    instance_buffer: BufferWrapper<Instance>,
+    draw_args: Vec<DrawIndexedIndirect>,
+    indirect_buffer: Buffer,
+    draw_mode: DrawMode,
     uniforms: Uniforms,
     uniform_buffer: BufferWrapper<Uniforms>,
     // We need to make sure this buffer isn't dropped before this struct is.
     #[allow(dead_code)]
     lighting_buffer: BufferWrapper<Lighting>,
+    #[allow(dead_code)]
+    orientation_buffer: BufferWrapper<OrientationTable>,
     uniform_bind_group: BindGroup,
     depth_texture: TextureWrapper,
     render_pipeline: RenderPipeline,
 
     /// This render engine's camera in 3d space.
     pub camera: Camera,
+    /// The current time in seconds, used for animating instances.
+    pub time: f32,
+    /// How long it takes an instance to finish growing, in seconds.
+    pub growth_duration: f32,
+    /// How long it takes a retired instance to fade away, in seconds.
+    pub retire_duration: f32,
+    /// The point in grid coordinates drawn at the origin.
+    pub grid_center: Vector3<f32>,
+    /// The clear transition currently playing, if any.
+    pub clear: Option<ClearAnimation>,
 }
 
 impl RenderEngine {
@@ -57,25 +78,47 @@ impl RenderEngine {
     ///
     /// Will return a RenderEngineCreationError if an error occurs while
     /// creating the engine.
+    #[allow(clippy::too_many_arguments)]
     pub fn new<B: BufRead>(
         device: &Device,
         queue: &Queue,
         window_size: FrameSize,
         color_format: TextureFormat,
         lighting: Lighting,
-        instances: &mut [B],
+        orientations: &OrientationTable,
+        mesh_data: &mut [B],
         instance_capacity: BufferAddress,
     ) -> Result<RenderEngine, RenderEngineCreationError> {
         let mut queue_submissions = vec![];
 
-        // setup instance managers
-        let mut instance_groups = vec![];
-        for instance_data in instances {
-            let (instance_manager, mut cb) =
-                InstanceManager::from_obj(device, instance_data, instance_capacity)?;
-            instance_groups.push(instance_manager);
-            queue_submissions.append(&mut cb);
-        }
+        // setup meshes, which all share the same buffers
+        let (meshes, mut mesh_cb) = MeshSet::load(device, mesh_data)?;
+        queue_submissions.append(&mut mesh_cb);
+
+        // setup an instance manager for each mesh, each with its own region of
+        // a shared instance buffer
+        let instance_groups: Vec<_> = (0..meshes.mesh_count() as BufferAddress)
+            .map(|index| InstanceManager::new(index * instance_capacity, instance_capacity))
+            .collect();
+        let instance_len = instance_groups
+            .last()
+            .map_or(0, InstanceManager::region_end);
+        let mut instance_buffer = BufferWrapper::new(device, instance_len, BufferUsages::VERTEX);
+        instance_buffer.resize(device, queue, instance_len);
+
+        // setup the indirect draw arguments of each instance group
+        let draw_args: Vec<_> = instance_groups
+            .iter()
+            .enumerate()
+            .map(|(index, group)| group.draw_args(meshes.range(index)))
+            .collect();
+        let indirect_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("indirect_buffer"),
+            contents: &draw_args_bytes(&draw_args),
+            usage: BufferUsages::INDIRECT | BufferUsages::COPY_DST,
+        });
+
+        let draw_mode = DrawMode::for_features(device.features());
 
         // setup camera
         let camera = Camera {
@@ -102,6 +145,11 @@ impl RenderEngine {
             BufferWrapper::from_data(device, &[lighting], BufferUsages::UNIFORM);
         queue_submissions.push(lighting_cb);
 
+        // setup the orientations instances are drawn with
+        let (orientation_buffer, orientation_cb) =
+            BufferWrapper::from_data(device, &[*orientations], BufferUsages::UNIFORM);
+        queue_submissions.push(orientation_cb);
+
         // setup uniform bind group
         let uniform_bind_group_layout =
             device.create_bind_group_layout(&BindGroupLayoutDescriptor {
@@ -126,6 +174,16 @@ impl RenderEngine {
                         },
                         count: None,
                     },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::VERTEX,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("uniform_bind_group_layout"),
             });
@@ -145,6 +203,12 @@ impl RenderEngine {
                         lighting_buffer.buffer().as_entire_buffer_binding(),
                     ),
                 },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::Buffer(
+                        orientation_buffer.buffer().as_entire_buffer_binding(),
+                    ),
+                },
             ],
             label: Some("uniform_bind_group"),
         });
@@ -207,11 +271,22 @@ impl RenderEngine {
 
         // return the result
         Ok(RenderEngine {
+            meshes,
             instance_groups,
+            instance_buffer,
+            draw_args,
+            indirect_buffer,
+            draw_mode,
             camera,
+            time: uniforms.time,
+            growth_duration: uniforms.growth_duration,
+            retire_duration: uniforms.retire_duration,
+            grid_center: uniforms.grid_center,
+            clear: None,
             uniforms,
             uniform_buffer,
             lighting_buffer,
+            orientation_buffer,
             uniform_bind_group,
             depth_texture,
             render_pipeline,
@@ -224,39 +299,48 @@ impl RenderEngine {
         self.depth_texture = TextureWrapper::new_depth(device, window_size, "depth_texture");
     }
 
-    /// Updates the data on the gpu to match the changes to this RenderEngine's
-    /// camera.
-    pub async fn update_camera(
-        &mut self,
-        device: &Device,
-    ) -> Result<CommandBuffer, BufferWriteError> {
+    /// Queues the data on the gpu to be updated to match the changes to this
+    /// RenderEngine's camera and animations.
+    pub fn update_uniforms(&mut self, device: &Device, queue: &Queue) {
         self.uniforms.update_camera(&self.camera);
-
-        Ok(self
-            .uniform_buffer
-            .replace_all(device, &[self.uniforms])
-            .await?)
+        self.uniforms.time = self.time;
+        self.uniforms.growth_duration = self.growth_duration;
+        self.uniforms.retire_duration = self.retire_duration;
+        self.uniforms.grid_center = self.grid_center;
+        self.uniforms.update_clear(self.clear);
+
+        self.uniform_buffer
+            .replace_all(device, queue, &[self.uniforms]);
     }
 
-    /// Adds instances to this render engine.
-    pub async fn add_instances(
+    /// Adds instances to one of this render engine's instance groups,
+    /// returning their handles.
+    pub fn add_instances(
         &mut self,
-        device: &Device,
         group_index: usize,
         instances: &[Instance],
-    ) -> Result<CommandBuffer, BufferWriteError> {
-        Ok(self.instance_groups[group_index]
-            .add_instances(device, instances)
-            .await?)
+    ) -> Vec<InstanceHandle> {
+        self.instance_groups[group_index].add_instances(instances)
     }
 
-    /// Removes a number of this render engine's last instances.
-    pub fn remove_instances(
+    /// Gets one of an instance group's instances.
+    pub fn instance(&self, group_index: usize, handle: InstanceHandle) -> Instance {
+        self.instance_groups[group_index].instance(handle)
+    }
+
+    /// Replaces one of an instance group's instances.
+    pub fn update_instance(
         &mut self,
         group_index: usize,
-        instances: BufferAddress,
-    ) -> Result<(), BufferRemoveError> {
-        self.instance_groups[group_index].remove_instances(instances)
+        handle: InstanceHandle,
+        instance: Instance,
+    ) {
+        self.instance_groups[group_index].update_instance(handle, instance);
+    }
+
+    /// Removes one of an instance group's instances, returning it.
+    pub fn remove_instance(&mut self, group_index: usize, handle: InstanceHandle) -> Instance {
+        self.instance_groups[group_index].remove_instance(handle)
     }
 
     /// Removes all instance from this render engine.
@@ -264,6 +348,50 @@ impl RenderEngine {
         self.instance_groups[group_index].clear_instances();
     }
 
+    /// Queues every instance change made since the last flush to be written
+    /// to the GPU, only writing the ranges of instances that changed.
+    pub fn flush_instances(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+    ) -> Result<(), BufferWriteError> {
+        // a group that outgrew its region moves along with every group after
+        // it, so the groups before it keep their instances where they are
+        if let Some(first) = self
+            .instance_groups
+            .iter()
+            .position(InstanceManager::outgrown)
+        {
+            let mut offset = first
+                .checked_sub(1)
+                .map_or(0, |previous| self.instance_groups[previous].region_end());
+            for group in self.instance_groups[first..].iter_mut() {
+                group.relocate(offset);
+                offset = group.region_end();
+            }
+            self.instance_buffer.resize(device, queue, offset);
+        }
+
+        for group in self.instance_groups.iter_mut() {
+            group.flush(device, queue, &mut self.instance_buffer)?;
+        }
+
+        // the draw arguments only change along with instance counts and regions
+        let draw_args: Vec<_> = self
+            .instance_groups
+            .iter()
+            .enumerate()
+            .map(|(index, group)| group.draw_args(self.meshes.range(index)))
+            .collect();
+        let bytes = draw_args_bytes(&draw_args);
+        if bytes != draw_args_bytes(&self.draw_args) {
+            queue.write_buffer(&self.indirect_buffer, 0, &bytes);
+            self.draw_args = draw_args;
+        }
+
+        Ok(())
+    }
+
     /// Performs a render.
     pub fn render(&mut self, device: &Device, view: &TextureView) -> CommandBuffer {
         let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
@@ -302,8 +430,33 @@ impl RenderEngine {
             render_pass.set_pipeline(&self.render_pipeline);
             render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
 
-            for group in self.instance_groups.iter() {
-                group.draw(&mut render_pass);
+            // every group's mesh and instances come from the same buffers, so
+            // all of them can be drawn at once
+            self.meshes.bind(&mut render_pass, 1);
+            render_pass.set_vertex_buffer(0, self.instance_buffer.buffer().slice(..));
+            match self.draw_mode {
+                DrawMode::MultiIndirect => render_pass.multi_draw_indexed_indirect(
+                    &self.indirect_buffer,
+                    0,
+                    self.draw_args.len() as u32,
+                ),
+                DrawMode::Indirect => {
+                    for index in 0..self.draw_args.len() {
+                        render_pass.draw_indexed_indirect(
+                            &self.indirect_buffer,
+                            (index * size_of::<DrawIndexedIndirect>()) as BufferAddress,
+                        );
+                    }
+                }
+                DrawMode::Direct => {
+                    for args in self.draw_args.iter() {
+                        render_pass.draw_indexed(
+                            args.base_index..args.base_index + args.vertex_count,
+                            args.vertex_offset,
+                            args.base_instance..args.base_instance + args.instance_count,
+                        );
+                    }
+                }
             }
         }
 
@@ -311,6 +464,41 @@ impl RenderEngine {
     }
 }
 
+/// How instance groups are drawn, depending on the device's features.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum DrawMode {
+    /// A single indirect multi-draw of every group.
+    MultiIndirect,
+    /// An indirect draw of each group.
+    Indirect,
+    /// A direct draw of each group with the same arguments, since indirect
+    /// draws can only start past the first instance with
+    /// INDIRECT_FIRST_INSTANCE.
+    Direct,
+}
+
+impl DrawMode {
+    /// Picks the draw mode making the most of the given features.
+    fn for_features(features: Features) -> DrawMode {
+        // every group's draw starts at its own region of the instance buffer
+        if !features.contains(Features::INDIRECT_FIRST_INSTANCE) {
+            DrawMode::Direct
+        } else if features.contains(Features::MULTI_DRAW_INDIRECT) {
+            DrawMode::MultiIndirect
+        } else {
+            DrawMode::Indirect
+        }
+    }
+}
+
+/// Lays out draw arguments the way the GPU reads them from an indirect buffer.
+fn draw_args_bytes(draw_args: &[DrawIndexedIndirect]) -> Vec<u8> {
+    draw_args
+        .iter()
+        .flat_map(|args| args.as_bytes().to_vec())
+        .collect()
+}
+
 /// Trait implemented by anything that can be put into a vertex buffer.
 pub trait VertexData {
     fn desc<'a>() -> VertexBufferLayout<'a>;
@@ -318,14 +506,15 @@ pub trait VertexData {
 
 /// Error potentially returned when creating a RenderEngine.
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum RenderEngineCreationError {
-    InstanceManagerCreationError(InstanceManagerCreationError),
+    MeshLoadError(MeshLoadError),
     IOError(io::Error),
 }
 
-impl From<InstanceManagerCreationError> for RenderEngineCreationError {
-    fn from(e: InstanceManagerCreationError) -> Self {
-        RenderEngineCreationError::InstanceManagerCreationError(e)
+impl From<MeshLoadError> for RenderEngineCreationError {
+    fn from(e: MeshLoadError) -> Self {
+        RenderEngineCreationError::MeshLoadError(e)
     }
 }
 
@@ -334,3 +523,23 @@ impl From<io::Error> for RenderEngineCreationError {
         RenderEngineCreationError::IOError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::render::DrawMode;
+    use wgpu::Features;
+
+    #[test]
+    fn test_draw_mode_for_features() {
+        let first_instance = Features::INDIRECT_FIRST_INSTANCE;
+        let multi_draw = Features::MULTI_DRAW_INDIRECT;
+
+        assert_eq!(
+            DrawMode::for_features(first_instance | multi_draw),
+            DrawMode::MultiIndirect
+        );
+        assert_eq!(DrawMode::for_features(first_instance), DrawMode::Indirect);
+        assert_eq!(DrawMode::for_features(multi_draw), DrawMode::Direct);
+        assert_eq!(DrawMode::for_features(Features::empty()), DrawMode::Direct);
+    }
+}
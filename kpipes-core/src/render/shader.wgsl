@@ -4,11 +4,43 @@
 
 const NUM_LIGHTS: u32 = 2u;
 
+// Instance packing, these must match the consts in instance.rs.
+const CELL_BITS: u32 = 10u;
+const ORIENTATION_COUNT: u32 = 95u;
+
+// Growing pieces extend along their local y axis, from the face of the previous
+// cell to the far side of their own piece. Pipe meshes end at GROWTH_TO, while
+// pieces reaching further, like balls and knuckles, show the rest once they're
+// fully grown. GROWTH_TO must match the const in the mesh tests.
+const GROWTH_FROM: f32 = -0.75;
+const GROWTH_TO: f32 = 0.25;
+
+// Clear transition styles, these must match the consts in uniforms.rs.
+const CLEAR_STYLE_NONE: u32 = 0u;
+const CLEAR_STYLE_FADE: u32 = 1u;
+const CLEAR_STYLE_SHRINK: u32 = 2u;
+const CLEAR_STYLE_DISSOLVE: u32 = 3u;
+
+// How much of the clear transition is spent waiting for older (or newer)
+// segments to go first.
+const CLEAR_STAGGER: f32 = 0.6;
+// How many dissolve cells fit in a single pipe segment.
+const DISSOLVE_SCALE: f32 = 8.0;
+
 // Structs
 
 // Matrix Uniform
 struct Uniforms {
     u_vp_matrix: mat4x4<f32>,
+    u_time: f32,
+    u_growth_duration: f32,
+    u_clear_start: f32,
+    u_clear_duration: f32,
+    u_clear_style: u32,
+    u_retire_duration: f32,
+    _padding2: u32,
+    _padding3: u32,
+    u_grid_center: vec3<f32>,
 }
 
 // Light
@@ -26,22 +58,29 @@ struct Lighting {
     _padding3: u32,
 }
 
+// Orientation Uniform
+struct Orientations {
+    u_matrices: array<mat4x4<f32>, ORIENTATION_COUNT>,
+}
+
 // Vertex Attributes
 struct VertexAttributes {
     @location(0)
-    s_color: vec3<f32>,
+    s_cell: u32,
     @location(1)
-    s_model_0: vec4<f32>,
+    s_orientation: u32,
     @location(2)
-    s_model_1: vec4<f32>,
-    @location(3)
-    s_model_2: vec4<f32>,
-    @location(4)
-    s_model_3: vec4<f32>,
+    s_color: vec4<f32>,
     @location(5)
     a_position: vec3<f32>,
     @location(6)
     a_normal: vec3<f32>,
+    @location(7)
+    s_growth_start: f32,
+    @location(8)
+    s_birth_time: f32,
+    @location(9)
+    s_retire_start: f32,
 }
 
 // Fragment Attributes
@@ -52,6 +91,12 @@ struct FragmentAttributes {
     v_color: vec3<f32>,
     @location(1)
     v_normal: vec3<f32>,
+    @location(2)
+    v_world_position: vec3<f32>,
+    @location(3)
+    v_alpha: f32,
+    @location(4)
+    v_dissolve: f32,
 }
 
 // Fragment Output
@@ -68,21 +113,89 @@ var<uniform> uniforms: Uniforms;
 @group(0)
 @binding(1)
 var<uniform> lighting: Lighting;
+@group(0)
+@binding(2)
+var<uniform> orientations: Orientations;
 
 // Vertex Shader
 
+// Gets how far through the clear transition a segment is, staggering segments by
+// their position in the order they are cleared in.
+fn clear_progress(progress: f32, order: f32) -> f32 {
+    return clamp((progress - order * CLEAR_STAGGER) / (1.0 - CLEAR_STAGGER), 0.0, 1.0);
+}
+
 @vertex
 fn vert_main(vertex: VertexAttributes) -> FragmentAttributes {
-    let s_model = mat4x4f(vertex.s_model_0, vertex.s_model_1, vertex.s_model_2, vertex.s_model_3);
+    // rebuild the model matrix from the instance's cell and orientation
+    let cell_mask = (1u << CELL_BITS) - 1u;
+    let cell = vec3f(
+        f32(vertex.s_cell & cell_mask),
+        f32((vertex.s_cell >> CELL_BITS) & cell_mask),
+        f32(vertex.s_cell >> (CELL_BITS * 2u)),
+    ) - uniforms.u_grid_center;
+    let translation = mat4x4f(
+        vec4f(1.0, 0.0, 0.0, 0.0),
+        vec4f(0.0, 1.0, 0.0, 0.0),
+        vec4f(0.0, 0.0, 1.0, 0.0),
+        vec4f(cell, 1.0),
+    );
+    let s_model = translation * orientations.u_matrices[vertex.s_orientation];
+
+    // clip the piece at its growth front, leaving fully grown pieces whole, and
+    // permanent instances like obstacles never grow at all
+    let progress = clamp((uniforms.u_time - vertex.s_growth_start) / uniforms.u_growth_duration, 0.0, 1.0);
+    var a_position = vertex.a_position;
+    if progress < 1.0 && vertex.s_birth_time >= 0.0 {
+        a_position.y = min(a_position.y, mix(GROWTH_FROM, GROWTH_TO, progress));
+    }
+
+    // animate the clear transition, with older segments having lower ages, and
+    // permanent instances having negative birth times
+    let clear = clamp((uniforms.u_time - uniforms.u_clear_start) / uniforms.u_clear_duration, 0.0, 1.0);
+    let age = clamp(vertex.s_birth_time / max(uniforms.u_clear_start, 0.001), 0.0, 1.0);
+    var alpha = 1.0;
+    var dissolve = 0.0;
+
+    if vertex.s_birth_time < 0.0 {
+        // permanent instances stay put
+    } else if uniforms.u_clear_style == CLEAR_STYLE_FADE {
+        alpha = 1.0 - clear_progress(clear, age);
+    } else if uniforms.u_clear_style == CLEAR_STYLE_SHRINK {
+        a_position *= 1.0 - clear_progress(clear, 1.0 - age);
+    } else if uniforms.u_clear_style == CLEAR_STYLE_DISSOLVE {
+        dissolve = clear;
+    }
 
-    let position = uniforms.u_vp_matrix * s_model * vec4f(vertex.a_position, 1.0);
+    // fade out retired segments
+    alpha *= 1.0 - clamp((uniforms.u_time - vertex.s_retire_start) / uniforms.u_retire_duration, 0.0, 1.0);
+
+    let world_position = s_model * vec4f(a_position, 1.0);
+    let position = uniforms.u_vp_matrix * world_position;
     let normal = normalize((s_model * vec4f(vertex.a_normal, 0.0)).xyz);
 
-    return FragmentAttributes(position, vertex.s_color, normal);
+    return FragmentAttributes(position, vertex.s_color.rgb, normal, world_position.xyz, alpha, dissolve);
 }
 
 // Fragment Shader
 
+// Gets a pseudo-random value between 0 and 1 for a point in space.
+fn hash(point: vec3<f32>) -> f32 {
+    return fract(sin(dot(point, vec3f(12.9898, 78.233, 37.719))) * 43758.5453);
+}
+
+// Gets a threshold between 0 and 1 for a pixel from a 4x4 ordered dither
+// pattern, so faded pieces can be drawn as a screen-door pattern instead of
+// being blended.
+fn dither_threshold(pixel: vec2<f32>) -> f32 {
+    let x = u32(pixel.x) % 4u;
+    let y = u32(pixel.y) % 4u;
+    let xy = x ^ y;
+    let index = ((xy & 1u) << 3u) | ((y & 1u) << 2u) | (xy & 2u) | ((y & 2u) >> 1u);
+
+    return (f32(index) + 0.5) / 16.0;
+}
+
 fn calc_darkness(normal: vec3<f32>, direction: vec3<f32>, strength: f32) -> f32 {
     let brightness = clamp(-dot(normal, direction) * strength, 0.0, 1.0);
 
@@ -91,6 +204,15 @@ fn calc_darkness(normal: vec3<f32>, direction: vec3<f32>, strength: f32) -> f32
 
 @fragment
 fn frag_main(fragment: FragmentAttributes) -> FragmentOutput {
+    // fading pieces are dithered rather than blended, so they still write depth
+    // without hiding the pieces behind them
+    if fragment.v_alpha < dither_threshold(fragment.position.xy) {
+        discard;
+    }
+    if hash(floor(fragment.v_world_position * DISSOLVE_SCALE)) < fragment.v_dissolve {
+        discard;
+    }
+
     let normal = normalize(fragment.v_normal);
 
     var darkness = 1.0 - lighting.u_ambient_light;
@@ -2,6 +2,8 @@ use crate::messages::FrameSize;
 use wgpu::{Device, Extent3d, Texture, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages, TextureView, TextureViewDescriptor};
 
 pub struct TextureWrapper {
+    // The view needs the texture to stay alive.
+    #[allow(dead_code)]
     pub texture: Texture,
     pub view: TextureView,
 }
@@ -1,12 +1,28 @@
-use crate::render::camera::Camera;
+use crate::{render::camera::Camera, settings::ClearTransition};
 use bytemuck::{Pod, Zeroable};
-use cgmath::{Matrix4, SquareMatrix};
+use cgmath::{Matrix4, SquareMatrix, Vector3};
+
+// These must match the CLEAR_STYLE consts in the shader.
+const CLEAR_STYLE_NONE: u32 = 0;
+const CLEAR_STYLE_FADE: u32 = 1;
+const CLEAR_STYLE_SHRINK: u32 = 2;
+const CLEAR_STYLE_DISSOLVE: u32 = 3;
 
 /// Holds data passed to the shaders in the form of uniforms.
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct Uniforms {
     pub vp_matrix: Matrix4<f32>,
+    pub time: f32,
+    pub growth_duration: f32,
+    pub clear_start: f32,
+    pub clear_duration: f32,
+    pub clear_style: u32,
+    pub retire_duration: f32,
+    pub _padding2: u32,
+    pub _padding3: u32,
+    pub grid_center: Vector3<f32>,
+    pub _padding4: u32,
 }
 
 unsafe impl Pod for Uniforms {}
@@ -16,10 +32,46 @@ impl Uniforms {
     pub fn new() -> Uniforms {
         Uniforms {
             vp_matrix: Matrix4::identity(),
+            time: 0.0,
+            growth_duration: 1.0,
+            clear_start: 0.0,
+            clear_duration: 1.0,
+            clear_style: CLEAR_STYLE_NONE,
+            retire_duration: 1.0,
+            _padding2: 0,
+            _padding3: 0,
+            grid_center: Vector3::new(0.0, 0.0, 0.0),
+            _padding4: 0,
         }
     }
 
     pub fn update_camera(&mut self, camera: &Camera) {
         self.vp_matrix = camera.build_vp_matrix();
     }
+
+    pub fn update_clear(&mut self, clear: Option<ClearAnimation>) {
+        match clear {
+            Some(clear) => {
+                self.clear_start = clear.start;
+                self.clear_duration = clear.duration;
+                self.clear_style = match clear.transition {
+                    ClearTransition::Instant => CLEAR_STYLE_NONE,
+                    ClearTransition::Fade => CLEAR_STYLE_FADE,
+                    ClearTransition::Shrink => CLEAR_STYLE_SHRINK,
+                    ClearTransition::Dissolve => CLEAR_STYLE_DISSOLVE,
+                };
+            }
+            None => self.clear_style = CLEAR_STYLE_NONE,
+        }
+    }
+}
+
+/// Describes a clear transition that is currently playing.
+#[derive(Debug, Copy, Clone)]
+pub struct ClearAnimation {
+    pub transition: ClearTransition,
+    /// The time the transition started, in seconds.
+    pub start: f32,
+    /// How long the transition lasts, in seconds.
+    pub duration: f32,
 }
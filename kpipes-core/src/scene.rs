@@ -0,0 +1,138 @@
+use crate::render::{instance::Instance, instance_manager::InstanceHandle, RenderEngine};
+use arrayvec::ArrayVec;
+use cgmath::Vector3;
+use std::collections::HashMap;
+
+/// The most instances a single segment can be drawn with.
+pub const MAX_SEGMENT_INSTANCES: usize = 3;
+
+/// Keeps track of the instances each segment on the board is drawn with, so
+/// they can be changed or removed wherever they are in their instance groups.
+pub struct SceneInstances {
+    group_count: usize,
+    slots: HashMap<SlotKey, ArrayVec<(usize, InstanceHandle), MAX_SEGMENT_INSTANCES>>,
+    next_retired: u64,
+}
+
+/// Identifies the instances of a segment that was retired, which are kept
+/// around while they fade away.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RetiredId(u64);
+
+/// Identifies the segment a set of instances is drawn for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+enum SlotKey {
+    /// A segment on the board, by location.
+    Segment(Vector3<usize>),
+    /// A segment that has left the board but is still fading away.
+    Retired(RetiredId),
+}
+
+impl SceneInstances {
+    /// Creates a new empty scene drawn with the first `group_count` instance
+    /// groups.
+    pub fn new(group_count: usize) -> SceneInstances {
+        SceneInstances {
+            group_count,
+            slots: HashMap::new(),
+            next_retired: 0,
+        }
+    }
+
+    /// Adds one of the instances for the segment at the given location.
+    pub fn insert(
+        &mut self,
+        renderer: &mut RenderEngine,
+        location: Vector3<usize>,
+        group_index: usize,
+        instance: Instance,
+    ) {
+        let slots = self.slots.entry(SlotKey::Segment(location)).or_default();
+        if slots.is_full() {
+            panic!(
+                "Inserted more than {} instances at {:?}",
+                MAX_SEGMENT_INSTANCES, location
+            );
+        }
+
+        let handle = renderer.add_instances(group_index, &[instance])[0];
+        slots.push((group_index, handle));
+    }
+
+    /// Removes every instance for the segment at the given location, returning
+    /// the first one inserted.
+    pub fn remove(&mut self, renderer: &mut RenderEngine, location: Vector3<usize>) -> Instance {
+        self.remove_slots(renderer, SlotKey::Segment(location))
+    }
+
+    /// Starts fading out every instance for the segment at the given location,
+    /// freeing the location for a new segment. The instances stay in the scene
+    /// until they are removed with [`remove_retired`](Self::remove_retired).
+    pub fn retire(
+        &mut self,
+        renderer: &mut RenderEngine,
+        location: Vector3<usize>,
+        retire_start: f32,
+    ) -> RetiredId {
+        let slots = self
+            .slots
+            .remove(&SlotKey::Segment(location))
+            .unwrap_or_else(|| panic!("No instance to retire at {:?}", location));
+
+        let id = RetiredId(self.next_retired);
+        self.next_retired += 1;
+
+        for &(group_index, handle) in slots.iter() {
+            let instance = Instance {
+                retire_start,
+                ..renderer.instance(group_index, handle)
+            };
+            renderer.update_instance(group_index, handle, instance);
+        }
+        self.slots.insert(SlotKey::Retired(id), slots);
+
+        id
+    }
+
+    /// Removes the instances of a retired segment.
+    pub fn remove_retired(&mut self, renderer: &mut RenderEngine, id: RetiredId) {
+        self.remove_slots(renderer, SlotKey::Retired(id));
+    }
+
+    /// Removes every instance for a segment, returning the first one inserted.
+    fn remove_slots(&mut self, renderer: &mut RenderEngine, key: SlotKey) -> Instance {
+        let slots = self
+            .slots
+            .remove(&key)
+            .unwrap_or_else(|| panic!("No instance to remove for {:?}", key));
+
+        let mut first = None;
+        for &(group_index, handle) in slots.iter() {
+            let instance = renderer.remove_instance(group_index, handle);
+            first.get_or_insert(instance);
+        }
+
+        first.unwrap()
+    }
+
+    /// Removes every instance.
+    pub fn clear(&mut self, renderer: &mut RenderEngine) {
+        for group_index in 0..self.group_count {
+            renderer.clear_instances(group_index);
+        }
+        self.slots.clear();
+    }
+
+    /// Replaces every instance with new ones.
+    pub fn rebuild<I: IntoIterator<Item = (Vector3<usize>, usize, Instance)>>(
+        &mut self,
+        renderer: &mut RenderEngine,
+        instances: I,
+    ) {
+        self.clear(renderer);
+
+        for (location, group_index, instance) in instances {
+            self.insert(renderer, location, group_index, instance);
+        }
+    }
+}
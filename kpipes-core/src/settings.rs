@@ -0,0 +1,286 @@
+use crate::{
+    color::{ColorGradient, ColorScheme},
+    obstacles::Obstacles,
+    recording::Recording,
+    snapshot::Snapshot,
+};
+use cgmath::Vector3;
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
+
+/// Describes the dimensions of the space pipes grow in.
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct GridSize {
+    pub width: usize,
+    pub height: usize,
+    pub depth: usize,
+}
+
+impl GridSize {
+    /// The longest a grid can be along any axis, since instances pack each
+    /// coordinate of their location into 10 bits.
+    pub const MAX_LENGTH: usize = 1024;
+
+    /// Creates a grid size, panicking if any length is zero or longer than
+    /// [`MAX_LENGTH`](GridSize::MAX_LENGTH).
+    pub fn new(width: usize, height: usize, depth: usize) -> GridSize {
+        let size = GridSize {
+            width,
+            height,
+            depth,
+        };
+        assert!(size.is_valid(), "Invalid grid size: {}", size);
+
+        size
+    }
+
+    /// Parses a grid size in the form `WxHxD`.
+    pub fn parse(value: &str) -> Option<GridSize> {
+        let mut dimensions = value.split('x').map(|d| d.parse::<usize>().ok());
+
+        let width = dimensions.next()??;
+        let height = dimensions.next()??;
+        let depth = dimensions.next()??;
+
+        let size = GridSize {
+            width,
+            height,
+            depth,
+        };
+
+        (dimensions.next().is_none() && size.is_valid()).then_some(size)
+    }
+
+    /// Gets whether every length of this size is at least 1 and no longer
+    /// than [`MAX_LENGTH`](GridSize::MAX_LENGTH).
+    pub fn is_valid(&self) -> bool {
+        [self.width, self.height, self.depth]
+            .iter()
+            .all(|length| (1..=GridSize::MAX_LENGTH).contains(length))
+    }
+
+    /// Gets the number of cells in a grid of this size.
+    pub fn volume(&self) -> usize {
+        self.width * self.height * self.depth
+    }
+
+    /// Gets whether a location is within the bounds of a grid of this size.
+    pub fn contains(&self, loc: Vector3<usize>) -> bool {
+        loc.x < self.width && loc.y < self.height && loc.z < self.depth
+    }
+
+    /// Gets the length of the diagonal of a grid of this size.
+    pub fn diagonal(&self) -> f32 {
+        ((self.width.pow(2) + self.height.pow(2) + self.depth.pow(2)) as f32).sqrt()
+    }
+
+    /// Gets the offset that moves the center of a grid of this size to the
+    /// origin.
+    pub fn center_offset(&self) -> Vector3<f32> {
+        Vector3::new(
+            (self.width as f32 - 1.0) / 2.0,
+            (self.height as f32 - 1.0) / 2.0,
+            (self.depth as f32 - 1.0) / 2.0,
+        )
+    }
+}
+
+impl Display for GridSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}x{}", self.width, self.height, self.depth)
+    }
+}
+
+impl Default for GridSize {
+    fn default() -> Self {
+        GridSize::new(20, 20, 20)
+    }
+}
+
+/// Weights controlling which direction a pipe grows in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GrowthRules {
+    /// The weight of continuing in a pipe's current direction, relative to
+    /// turning in any one other direction.
+    pub straight_weight: f32,
+    /// How many segments a pipe must grow in a straight line before it is
+    /// allowed to turn, unless it is blocked.
+    pub min_straight_run: u32,
+    /// Multiplier applied to the weights of moving up or down, including
+    /// diagonally.
+    pub vertical_weight: f32,
+    /// How many steps ahead pipes look for free space before choosing a
+    /// direction, avoiding directions that lead into smaller pockets. Zero
+    /// disables looking ahead.
+    pub lookahead: u32,
+    /// Whether pipes can also move diagonally, towards the spaces sharing an
+    /// edge or a corner instead of only those sharing a face.
+    pub diagonal: bool,
+}
+
+impl Default for GrowthRules {
+    fn default() -> Self {
+        GrowthRules {
+            straight_weight: 1.0,
+            min_straight_run: 0,
+            vertical_weight: 1.0,
+            lookahead: 0,
+            diagonal: false,
+        }
+    }
+}
+
+/// Limits on how full the board gets in rolling mode, where the oldest
+/// finished pipes are retired to make room for new ones instead of the board
+/// being cleared.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RollingRules {
+    /// The most pipes allowed on the board at once, or `None` for no limit.
+    /// Pipes that are still growing are never retired, so this can't be less
+    /// than the number of pipes growing at once.
+    pub max_pipes: Option<usize>,
+    /// The fraction of the spaces not blocked by obstacles that pipes can
+    /// fill before the oldest pipes are retired, between 0 and 1.
+    pub max_fill: f32,
+}
+
+impl Default for RollingRules {
+    fn default() -> Self {
+        RollingRules {
+            max_pipes: None,
+            max_fill: 0.6,
+        }
+    }
+}
+
+/// How pipes are drawn where they turn.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum JointStyle {
+    /// Mitred elbows.
+    Mitred,
+    /// Balls joining the two straight halves of the turn.
+    Ball,
+    /// Either mitred elbows or ball joints, picked at random for each pipe.
+    Mixed,
+}
+
+impl JointStyle {
+    /// Gets the lowercase name of this joint style.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            JointStyle::Mitred => "mitred",
+            JointStyle::Ball => "ball",
+            JointStyle::Mixed => "mixed",
+        }
+    }
+
+    /// Gets the joint style with the given lowercase name.
+    pub fn from_name(name: &str) -> Option<JointStyle> {
+        match name {
+            "mitred" => Some(JointStyle::Mitred),
+            "ball" => Some(JointStyle::Ball),
+            "mixed" => Some(JointStyle::Mixed),
+            _ => None,
+        }
+    }
+}
+
+/// How the board animates away when it is cleared.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ClearTransition {
+    /// Removes every pipe at once.
+    Instant,
+    /// Fades pipes out, oldest segments first.
+    Fade,
+    /// Shrinks pipes away, newest segments first.
+    Shrink,
+    /// Dissolves the whole scene at once.
+    Dissolve,
+}
+
+/// Settings controlling how pipes are generated and displayed.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// The dimensions of the space pipes grow in.
+    pub grid_size: GridSize,
+    /// Whether pipes leaving one side of the grid wrap around to the opposite
+    /// side instead of being blocked.
+    pub wrap: bool,
+    /// How many pipes grow at the same time.
+    pub pipe_count: usize,
+    /// The seed used for generating pipes, or `None` to pick a random seed.
+    pub seed: Option<u64>,
+    /// Weights controlling which direction pipes grow in.
+    pub growth_rules: GrowthRules,
+    /// The chance of a bend being drawn as a teapot, between 0 and 1.
+    pub teapot_chance: f32,
+    /// How pipes are drawn where they turn.
+    pub joint_style: JointStyle,
+    /// How new pipes are colored.
+    pub color_scheme: ColorScheme,
+    /// How each pipe's color changes along its length.
+    pub color_gradient: ColorGradient,
+    /// How the board animates away when it is cleared.
+    pub clear_transition: ClearTransition,
+    /// Limits for retiring the oldest pipes instead of clearing the board, or
+    /// `None` to clear the board once it fills up.
+    pub rolling: Option<RollingRules>,
+    /// How long retired pipes take to fade away.
+    pub retire_duration: Duration,
+    /// Obstacles pipes must grow around.
+    pub obstacles: Obstacles,
+    /// Whether obstacles are drawn, or only shown by the pipes growing around
+    /// them.
+    pub show_obstacles: bool,
+    /// A recording to replay instead of generating new pipes. The recording's
+    /// grid size, pipe count, seed, wrap, obstacles and color gradient are
+    /// used instead of the ones above.
+    pub replay: Option<Recording>,
+    /// A snapshot to continue from instead of starting with an empty board.
+    /// The snapshot's grid size, pipes, obstacles and growth settings are
+    /// used instead of the ones above.
+    pub snapshot: Option<Snapshot>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            grid_size: Default::default(),
+            wrap: false,
+            pipe_count: 1,
+            seed: None,
+            growth_rules: Default::default(),
+            teapot_chance: 0.005,
+            joint_style: JointStyle::Mitred,
+            color_scheme: ColorScheme::Random,
+            color_gradient: ColorGradient::None,
+            clear_transition: ClearTransition::Fade,
+            rolling: None,
+            retire_duration: Duration::from_millis(1500),
+            obstacles: Obstacles::None,
+            show_obstacles: true,
+            replay: None,
+            snapshot: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::GridSize;
+
+    #[test]
+    fn test_parse_grid_size_within_limit() {
+        assert_eq!(GridSize::parse("1024x1x2"), Some(GridSize::new(1024, 1, 2)));
+        assert_eq!(GridSize::parse("1025x1x2"), None);
+        assert_eq!(GridSize::parse("4x0x4"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid grid size")]
+    fn test_new_grid_size_past_limit() {
+        GridSize::new(4, GridSize::MAX_LENGTH + 1, 4);
+    }
+}
@@ -0,0 +1,1494 @@
+use crate::{
+    color::{random_color, ColorGradient, ColorPicker},
+    direction::Direction,
+    obstacles::Obstacles,
+    recording::{RecordedAction, RecordedEntry, Recording},
+    settings::{GridSize, GrowthRules, JointStyle, RollingRules, Settings},
+    spaces::Spaces,
+};
+use arrayvec::ArrayVec;
+use cgmath::Vector3;
+use rand::{thread_rng, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::{
+    collections::VecDeque,
+    fmt::{Display, Formatter},
+    mem,
+    time::Duration,
+};
+
+/// How long to wait before causing a pipe to grow.
+pub const GROWTH_DURATION: Duration = Duration::from_millis(50);
+/// How many times to try to spawn a new pipe before clearing all the pipes, or
+/// retiring the oldest one in rolling mode.
+const MAX_START_ATTEMPTS: u32 = 3;
+
+/// Describes the shape and orientation of a single pipe segment.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SegmentKind {
+    /// A freshly started pipe that is not yet connected to anything.
+    Single,
+    /// The first segment of a pipe, leaving in the given direction.
+    Start(Direction),
+    /// A segment passing straight through in the given direction.
+    Straight(Direction),
+    /// A segment entered moving `from` and left moving `to`.
+    Bent { from: Direction, to: Direction },
+    /// A ball joint standing in for a bent segment.
+    BallJoint { from: Direction, to: Direction },
+    /// A teapot standing in for a bent segment.
+    Teapot { from: Direction, to: Direction },
+    /// The last segment of a pipe, entered moving in the given direction.
+    End(Direction),
+}
+
+impl SegmentKind {
+    /// Gets the kind of an intermediate segment entered moving `from` and left
+    /// moving `to`.
+    pub fn joint(from: Direction, to: Direction) -> SegmentKind {
+        if from == to {
+            SegmentKind::Straight(to)
+        } else {
+            SegmentKind::Bent { from, to }
+        }
+    }
+
+    /// Gets the direction a segment of this kind was entered moving in, if
+    /// any.
+    pub fn entry(&self) -> Option<Direction> {
+        match *self {
+            SegmentKind::Single | SegmentKind::Start(_) => None,
+            SegmentKind::Straight(direction) | SegmentKind::End(direction) => Some(direction),
+            SegmentKind::Bent { from, .. }
+            | SegmentKind::BallJoint { from, .. }
+            | SegmentKind::Teapot { from, .. } => Some(from),
+        }
+    }
+
+    /// Gets the direction a segment of this kind was left moving in, if any.
+    pub fn exit(&self) -> Option<Direction> {
+        match *self {
+            SegmentKind::Single | SegmentKind::End(_) => None,
+            SegmentKind::Start(direction) | SegmentKind::Straight(direction) => Some(direction),
+            SegmentKind::Bent { to, .. }
+            | SegmentKind::BallJoint { to, .. }
+            | SegmentKind::Teapot { to, .. } => Some(to),
+        }
+    }
+}
+
+/// Identifies a pipe from when it is started until the board is cleared.
+///
+/// Unlike the growth slot a pipe is grown in, which is reused once the pipe is
+/// finished, every pipe gets its own ID.
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct PipeId(pub(crate) u64);
+
+impl Display for PipeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Describes a single pipe segment placed on the board.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Segment {
+    pub location: Vector3<usize>,
+    pub kind: SegmentKind,
+    pub color: Vector3<f32>,
+    /// The pipe this segment belongs to.
+    pub pipe: PipeId,
+}
+
+/// Describes a change to the board made by the simulation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PipeEvent {
+    /// A new segment was placed on the board.
+    SegmentPlaced(Segment),
+    /// An existing segment was replaced by a segment of a different kind.
+    SegmentRetyped { old: Segment, new: Segment },
+    /// A segment was removed from the board along with the rest of its pipe.
+    SegmentRemoved(Segment),
+    /// All segments were removed from the board.
+    BoardCleared,
+}
+
+/// Describes a single pipe that is still growing.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct Pipe {
+    pub(crate) color: Vector3<f32>,
+    pub(crate) head: Option<Segment>,
+    pub(crate) straight_run: u32,
+    /// How many segments this pipe has grown since its first one.
+    pub(crate) length: u32,
+    /// How this pipe's turns are drawn, never [`JointStyle::Mixed`].
+    pub(crate) joint_style: JointStyle,
+    pub(crate) time_since_growth: Duration,
+}
+
+/// Everything needed to restore a simulation exactly as it was.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationSnapshot {
+    pub(crate) grid_size: GridSize,
+    pub(crate) wrap: bool,
+    pub(crate) growth_rules: GrowthRules,
+    pub(crate) teapot_chance: f32,
+    pub(crate) joint_style: JointStyle,
+    pub(crate) colors: ColorPicker,
+    pub(crate) color_gradient: ColorGradient,
+    pub(crate) rolling: Option<RollingRules>,
+    pub(crate) seed: u64,
+    pub(crate) rand_seed: [u8; 32],
+    pub(crate) rand_stream: u64,
+    pub(crate) rand_word_pos: u128,
+    pub(crate) time: Duration,
+    pub(crate) pipes: Vec<Pipe>,
+    pub(crate) next_pipe_id: u64,
+    /// Every segment on the board, by pipe from oldest to newest and in the
+    /// order each pipe's segments were placed.
+    pub(crate) segments: Vec<Segment>,
+    /// Every space blocked by an obstacle, sorted by location.
+    pub(crate) obstacles: Vec<Vector3<usize>>,
+}
+
+/// Headless pipe growth simulation.
+///
+/// This handles all the growth logic without touching the GPU. Each step
+/// describes its changes to the board as a series of [`PipeEvent`]s.
+pub struct PipeSimulation {
+    spaces: Spaces,
+    growth_rules: GrowthRules,
+    teapot_chance: f32,
+    joint_style: JointStyle,
+    colors: ColorPicker,
+    color_gradient: ColorGradient,
+    /// Limits for retiring the oldest pipes, if the board is never cleared.
+    rolling: Option<RollingRules>,
+    seed: u64,
+    rand: ChaCha8Rng,
+    pipes: Vec<Pipe>,
+    /// The ID the next pipe started will get.
+    next_pipe_id: u64,
+    /// How long this simulation has been updated for.
+    time: Duration,
+    /// Actions taken so far, if recording.
+    recorded: Option<Vec<RecordedEntry>>,
+    /// Actions still to be taken, if replaying a recording.
+    replay: Option<VecDeque<RecordedEntry>>,
+}
+
+impl PipeSimulation {
+    /// Creates a new simulation with an empty board.
+    ///
+    /// Simulations created with the same seed and settings will generate the
+    /// same pipes when stepped the same way.
+    pub fn new(settings: &Settings) -> PipeSimulation {
+        let seed = settings.seed.unwrap_or_else(|| thread_rng().gen());
+        let mut rand = ChaCha8Rng::seed_from_u64(seed);
+
+        // stagger the pipes' growth timers so they don't all grow at once
+        let pipe_count = settings.pipe_count as u32;
+        let pipes = (0..pipe_count)
+            .map(|index| Pipe {
+                color: random_color(&mut rand),
+                head: None,
+                straight_run: 0,
+                length: 0,
+                joint_style: JointStyle::Mitred,
+                time_since_growth: GROWTH_DURATION * index / pipe_count,
+            })
+            .collect();
+
+        let obstacles = settings.obstacles.build(settings.grid_size);
+        let mut spaces = Spaces::new(obstacles.size(), settings.wrap);
+        for location in obstacles.locations() {
+            spaces.set_obstacle(location);
+        }
+
+        PipeSimulation {
+            spaces,
+            growth_rules: settings.growth_rules,
+            teapot_chance: settings.teapot_chance,
+            joint_style: settings.joint_style,
+            colors: ColorPicker::new(settings.color_scheme.clone()),
+            color_gradient: settings.color_gradient,
+            rolling: settings.rolling,
+            seed,
+            rand,
+            pipes,
+            next_pipe_id: 0,
+            time: Duration::ZERO,
+            recorded: None,
+            replay: None,
+        }
+    }
+
+    /// Creates a new simulation that grows pipes by replaying a recording
+    /// instead of making its own choices.
+    pub fn replay(recording: &Recording) -> PipeSimulation {
+        let mut simulation = PipeSimulation::new(&Settings {
+            grid_size: recording.grid_size(),
+            pipe_count: recording.pipe_count(),
+            seed: Some(recording.seed()),
+            wrap: recording.wraps(),
+            color_gradient: recording.color_gradient(),
+            obstacles: Obstacles::Mask(recording.obstacles().clone()),
+            ..Default::default()
+        });
+        simulation.replay = Some(recording.entries().iter().copied().collect());
+        simulation
+    }
+
+    /// Restores a simulation from a snapshot, continuing exactly as the
+    /// snapshotted simulation would have.
+    pub fn restore(snapshot: &SimulationSnapshot) -> PipeSimulation {
+        let mut rand = ChaCha8Rng::from_seed(snapshot.rand_seed);
+        rand.set_stream(snapshot.rand_stream);
+        rand.set_word_pos(snapshot.rand_word_pos);
+
+        let mut spaces = Spaces::new(snapshot.grid_size, snapshot.wrap);
+        for &location in snapshot.obstacles.iter() {
+            spaces.set_obstacle(location);
+        }
+        for &segment in snapshot.segments.iter() {
+            spaces.place(segment);
+        }
+
+        PipeSimulation {
+            spaces,
+            growth_rules: snapshot.growth_rules,
+            teapot_chance: snapshot.teapot_chance,
+            joint_style: snapshot.joint_style,
+            colors: snapshot.colors.clone(),
+            color_gradient: snapshot.color_gradient,
+            rolling: snapshot.rolling,
+            seed: snapshot.seed,
+            rand,
+            pipes: snapshot.pipes.clone(),
+            next_pipe_id: snapshot.next_pipe_id,
+            time: snapshot.time,
+            recorded: None,
+            replay: None,
+        }
+    }
+
+    /// Captures everything needed to restore this simulation later.
+    ///
+    /// Recording and replaying are not part of the snapshot.
+    pub fn snapshot(&self) -> SimulationSnapshot {
+        SimulationSnapshot {
+            grid_size: self.spaces.size(),
+            wrap: self.spaces.wraps(),
+            growth_rules: self.growth_rules,
+            teapot_chance: self.teapot_chance,
+            joint_style: self.joint_style,
+            colors: self.colors.clone(),
+            color_gradient: self.color_gradient,
+            rolling: self.rolling,
+            seed: self.seed,
+            rand_seed: self.rand.get_seed(),
+            rand_stream: self.rand.get_stream(),
+            rand_word_pos: self.rand.get_word_pos(),
+            time: self.time,
+            pipes: self.pipes.clone(),
+            next_pipe_id: self.next_pipe_id,
+            segments: self.spaces.segments().copied().collect(),
+            obstacles: self.spaces.obstacles().collect(),
+        }
+    }
+
+    /// Advances every pipe's growth timer, growing the pipes whose timers have
+    /// run out and appending the resulting events to `events`.
+    ///
+    /// When replaying, this instead takes every recorded action that is due.
+    pub fn update(&mut self, delta: Duration, events: &mut Vec<PipeEvent>) {
+        self.time += delta;
+
+        if self.replay.is_some() {
+            while let Some(entry) = self.next_due_entry() {
+                let legal = self.apply_action(entry.action, events);
+                assert!(legal, "Illegal action in validated recording: {:?}", entry);
+            }
+            return;
+        }
+
+        for index in 0..self.pipes.len() {
+            let pipe = &mut self.pipes[index];
+            pipe.time_since_growth += delta;
+            if pipe.time_since_growth > GROWTH_DURATION {
+                pipe.time_since_growth -= GROWTH_DURATION;
+                self.grow(index, events);
+            }
+        }
+    }
+
+    /// Performs a growth step for every pipe, appending the resulting events to
+    /// `events`.
+    ///
+    /// Does nothing when replaying, as recorded actions are only taken by
+    /// [`update`](PipeSimulation::update).
+    pub fn step(&mut self, events: &mut Vec<PipeEvent>) {
+        if self.replay.is_some() {
+            return;
+        }
+
+        for index in 0..self.pipes.len() {
+            self.grow(index, events);
+        }
+    }
+
+    /// Gets the seed used for generating pipes.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Gets the dimensions of the space pipes grow in.
+    pub fn grid_size(&self) -> GridSize {
+        self.spaces.size()
+    }
+
+    /// Gets whether pipes leaving one side of the grid wrap around to the
+    /// other.
+    pub fn wraps(&self) -> bool {
+        self.spaces.wraps()
+    }
+
+    /// Gets how each pipe's color changes along its length.
+    pub fn color_gradient(&self) -> ColorGradient {
+        self.color_gradient
+    }
+
+    /// Gets the number of pipes growing at the same time.
+    pub fn pipe_count(&self) -> usize {
+        self.pipes.len()
+    }
+
+    /// Gets every segment on the board, by pipe from oldest to newest and in
+    /// the order each pipe's segments were placed.
+    pub fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.spaces.segments()
+    }
+
+    /// Gets the grid pipes grow in, which can be queried for the segment and
+    /// pipe in each space.
+    pub fn spaces(&self) -> &Spaces {
+        &self.spaces
+    }
+
+    /// Gets the location of every space blocked by an obstacle.
+    pub fn obstacles(&self) -> impl Iterator<Item = Vector3<usize>> + '_ {
+        self.spaces.obstacles()
+    }
+
+    /// Gets whether this simulation is replaying a recording.
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// Starts recording every action this simulation takes.
+    pub fn start_recording(&mut self) {
+        self.recorded.get_or_insert_with(Vec::new);
+    }
+
+    /// Stops recording, discarding anything not yet taken.
+    pub fn stop_recording(&mut self) {
+        self.recorded = None;
+    }
+
+    /// Takes the actions recorded since this was last called.
+    pub fn take_recorded(&mut self) -> Vec<RecordedEntry> {
+        self.recorded.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    /// Clears all the pipes.
+    pub fn clear_pipes(&mut self, events: &mut Vec<PipeEvent>) {
+        self.spaces.clear();
+        for pipe in self.pipes.iter_mut() {
+            pipe.head = None;
+        }
+        events.push(PipeEvent::BoardCleared);
+
+        self.record(RecordedAction::Clear);
+    }
+
+    /// Gets the oldest pipe still on the board, if any.
+    pub fn oldest_pipe(&self) -> Option<PipeId> {
+        self.spaces.pipes().next()
+    }
+
+    /// Removes every segment of a pipe from the board, returning whether the
+    /// pipe was on the board. If the pipe is still growing, a new pipe is
+    /// started in its place.
+    pub fn remove_pipe(&mut self, pipe: PipeId, events: &mut Vec<PipeEvent>) -> bool {
+        let segments = self.spaces.remove_pipe(pipe);
+        if segments.is_empty() {
+            return false;
+        }
+
+        events.extend(segments.into_iter().map(PipeEvent::SegmentRemoved));
+        for slot in self.pipes.iter_mut() {
+            if slot.head.map(|head| head.pipe) == Some(pipe) {
+                slot.head = None;
+            }
+        }
+
+        self.record(RecordedAction::Remove { pipe });
+        true
+    }
+
+    /// Takes a recorded action, returning whether it was legal. Nothing is
+    /// changed if it wasn't.
+    pub(crate) fn apply_action(
+        &mut self,
+        action: RecordedAction,
+        events: &mut Vec<PipeEvent>,
+    ) -> bool {
+        match action {
+            RecordedAction::Start {
+                pipe,
+                location,
+                color,
+                joint_style,
+            } => {
+                if pipe >= self.pipes.len()
+                    || !self.spaces.size().contains(location)
+                    || self.spaces.get_vec(location)
+                    || joint_style == JointStyle::Mixed
+                {
+                    return false;
+                }
+
+                self.start_pipe(pipe, events, location, color, joint_style);
+            }
+            RecordedAction::Grow {
+                pipe,
+                direction,
+                teapot,
+            } => {
+                let prev = match self.pipes.get(pipe).and_then(|pipe| pipe.head) {
+                    Some(prev) => prev,
+                    None => return false,
+                };
+
+                if !self.spaces.is_move_clear(prev.location, direction) {
+                    return false;
+                }
+
+                self.extend_pipe(pipe, events, prev, direction, teapot);
+            }
+            RecordedAction::Clear => self.clear_pipes(events),
+            RecordedAction::Remove { pipe } => return self.remove_pipe(pipe, events),
+        }
+
+        true
+    }
+
+    /// Takes the next recorded action if it is due.
+    fn next_due_entry(&mut self) -> Option<RecordedEntry> {
+        let replay = self.replay.as_mut()?;
+        if replay.front()?.time <= self.time {
+            replay.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// Records an action, if recording.
+    fn record(&mut self, action: RecordedAction) {
+        if let Some(recorded) = &mut self.recorded {
+            recorded.push(RecordedEntry {
+                time: self.time,
+                action,
+            });
+        }
+    }
+
+    /// Performs a growth step for a single pipe (either growing it, starting a
+    /// new one in its place, or clearing the board).
+    fn grow(&mut self, index: usize, events: &mut Vec<PipeEvent>) {
+        if let Some(prev) = self.pipes[index].head {
+            self.grow_existing(index, events, prev);
+        } else {
+            self.new_pipe(index, events);
+        };
+    }
+
+    /// Places a pipe segment connected to an existing pipe, changing the
+    /// previous segment's kind as needed. Will start a new pipe in its place if
+    /// the pipe is boxed in.
+    fn grow_existing(&mut self, index: usize, events: &mut Vec<PipeEvent>, prev: Segment) {
+        let prev_direction = head_direction(prev);
+
+        let mut directions = ArrayVec::<(Direction, f32), 26>::new();
+
+        let candidates: ArrayVec<Direction, 26> = if self.growth_rules.diagonal {
+            enum_iterator::all::<Direction>().collect()
+        } else {
+            Direction::axes().collect()
+        };
+
+        for direction in candidates {
+            if self.spaces.is_move_clear(prev.location, direction) {
+                directions.push((direction, self.direction_weight(prev_direction, direction)));
+            }
+        }
+
+        if self.growth_rules.lookahead > 0 {
+            self.avoid_pockets(prev.location, &mut directions);
+        }
+
+        if directions.is_empty() {
+            self.new_pipe(index, events);
+        } else {
+            // turning is only allowed once the pipe has gone straight for long
+            // enough, unless going straight is blocked
+            let must_go_straight = prev_direction.filter(|&prev_direction| {
+                self.pipes[index].straight_run < self.growth_rules.min_straight_run
+                    && directions.iter().any(|&(d, _)| d == prev_direction)
+            });
+
+            let direction =
+                must_go_straight.unwrap_or_else(|| choose_weighted(&mut self.rand, &directions));
+
+            // every so often, a bend is replaced by a teapot, as long as it
+            // only turns between axes
+            let bent = prev_direction.is_some_and(|prev_direction| {
+                prev_direction != direction
+                    && !prev_direction.is_diagonal()
+                    && !direction.is_diagonal()
+            });
+            let teapot =
+                bent && self.teapot_chance > 0.0 && self.rand.gen_bool(self.teapot_chance as f64);
+
+            self.extend_pipe(index, events, prev, direction, teapot);
+        }
+    }
+
+    /// Removes every direction leading into a smaller pocket of free space than
+    /// another direction, so pipes don't box themselves in.
+    fn avoid_pockets(
+        &self,
+        location: Vector3<usize>,
+        directions: &mut ArrayVec<(Direction, f32), 26>,
+    ) {
+        // pockets reaching further than the lookahead are as good as open space
+        let volumes: ArrayVec<usize, 26> = directions
+            .iter()
+            .map(|&(direction, _)| {
+                self.spaces
+                    .enclosed_volume(
+                        self.spaces.neighbor(location, direction).unwrap(),
+                        self.growth_rules.lookahead,
+                    )
+                    .unwrap_or(usize::MAX)
+            })
+            .collect();
+
+        if let Some(&largest) = volumes.iter().max() {
+            *directions = directions
+                .iter()
+                .zip(volumes)
+                .filter(|&(_, volume)| volume == largest)
+                .map(|(&direction, _)| direction)
+                .collect();
+        }
+    }
+
+    /// Places a pipe segment connected to an existing pipe in the given
+    /// direction.
+    fn extend_pipe(
+        &mut self,
+        index: usize,
+        events: &mut Vec<PipeEvent>,
+        prev: Segment,
+        direction: Direction,
+        teapot: bool,
+    ) {
+        let prev_direction = head_direction(prev);
+        let location = self.spaces.neighbor(prev.location, direction).unwrap();
+
+        let kind = match prev_direction {
+            None => SegmentKind::Start(direction),
+            Some(prev_direction) => SegmentKind::joint(prev_direction, direction),
+        };
+
+        let kind = match kind {
+            SegmentKind::Bent { from, to } if teapot => SegmentKind::Teapot { from, to },
+            SegmentKind::Bent { from, to } if self.pipes[index].joint_style == JointStyle::Ball => {
+                SegmentKind::BallJoint { from, to }
+            }
+            kind => kind,
+        };
+        let pipe = &mut self.pipes[index];
+        pipe.straight_run = if prev_direction == Some(direction) {
+            pipe.straight_run + 1
+        } else {
+            1
+        };
+        pipe.length += 1;
+
+        let retyped = Segment { kind, ..prev };
+        events.push(PipeEvent::SegmentRetyped {
+            old: prev,
+            new: retyped,
+        });
+        self.spaces.place(retyped);
+
+        let pipe = &self.pipes[index];
+        let endpoint = Segment {
+            location,
+            kind: SegmentKind::End(direction),
+            color: self
+                .color_gradient
+                .apply(pipe.color, pipe.length, location, self.spaces.size()),
+            pipe: prev.pipe,
+        };
+        events.push(PipeEvent::SegmentPlaced(endpoint));
+
+        self.spaces.place(endpoint);
+        self.pipes[index].head = Some(endpoint);
+
+        self.record(RecordedAction::Grow {
+            pipe: index,
+            direction,
+            teapot: matches!(kind, SegmentKind::Teapot { .. }),
+        });
+    }
+
+    /// Starts growing a new pipe. Will clear the pipes and start over if a
+    /// suitable location cannot be found, unless rolling, in which case the
+    /// oldest pipes are retired to make room instead.
+    fn new_pipe(&mut self, index: usize, events: &mut Vec<PipeEvent>) {
+        // the pipe grown in this slot is finished, so it can be retired too
+        self.pipes[index].head = None;
+        self.retire_pipes(events);
+
+        let mut attempts = 0;
+
+        let location = loop {
+            if attempts >= MAX_START_ATTEMPTS && self.rolling.is_some() {
+                if let Some(oldest) = self.oldest_finished_pipe() {
+                    self.remove_pipe(oldest, events);
+                    attempts = 0;
+                    continue;
+                }
+            }
+
+            if attempts >= MAX_START_ATTEMPTS {
+                self.clear_pipes(events);
+
+                // only obstacles are left, and they never fill the whole board
+                break loop {
+                    let location = random_location(&mut self.rand, self.spaces.size());
+                    if !self.spaces.get_vec(location) {
+                        break location;
+                    }
+                };
+            }
+
+            let location = random_location(&mut self.rand, self.spaces.size());
+            if !self.spaces.get_vec(location) {
+                break location;
+            }
+
+            attempts += 1;
+        };
+
+        let color = self.colors.pick(&mut self.rand);
+
+        let joint_style = match self.joint_style {
+            JointStyle::Mixed => {
+                if self.rand.gen() {
+                    JointStyle::Ball
+                } else {
+                    JointStyle::Mitred
+                }
+            }
+            joint_style => joint_style,
+        };
+
+        self.start_pipe(index, events, location, color, joint_style);
+    }
+
+    /// Retires the oldest finished pipes until there is room for another pipe
+    /// within the rolling limits. Pipes that are still growing are never
+    /// retired. Does nothing if not rolling.
+    fn retire_pipes(&mut self, events: &mut Vec<PipeEvent>) {
+        let Some(rules) = self.rolling else {
+            return;
+        };
+
+        while rules
+            .max_pipes
+            .is_some_and(|max| self.spaces.pipe_count() >= max)
+            || self.spaces.fill_fraction() > rules.max_fill
+        {
+            let Some(oldest) = self.oldest_finished_pipe() else {
+                break;
+            };
+            self.remove_pipe(oldest, events);
+        }
+    }
+
+    /// Gets the oldest pipe on the board that isn't growing any more, if any.
+    fn oldest_finished_pipe(&self) -> Option<PipeId> {
+        self.spaces.pipes().find(|&pipe| {
+            !self
+                .pipes
+                .iter()
+                .any(|slot| slot.head.is_some_and(|head| head.pipe == pipe))
+        })
+    }
+
+    /// Places the first segment of a new pipe.
+    fn start_pipe(
+        &mut self,
+        index: usize,
+        events: &mut Vec<PipeEvent>,
+        location: Vector3<usize>,
+        color: Vector3<f32>,
+        joint_style: JointStyle,
+    ) {
+        let start = Segment {
+            location,
+            kind: SegmentKind::Single,
+            color: self
+                .color_gradient
+                .apply(color, 0, location, self.spaces.size()),
+            pipe: PipeId(self.next_pipe_id),
+        };
+        self.next_pipe_id += 1;
+        events.push(PipeEvent::SegmentPlaced(start));
+
+        self.spaces.place(start);
+        let pipe = &mut self.pipes[index];
+        pipe.color = color;
+        pipe.head = Some(start);
+        pipe.straight_run = 0;
+        pipe.length = 0;
+        pipe.joint_style = joint_style;
+
+        self.record(RecordedAction::Start {
+            pipe: index,
+            location,
+            color,
+            joint_style,
+        });
+    }
+
+    /// Gets the weight of a pipe growing in `direction`, according to the
+    /// growth rules.
+    fn direction_weight(&self, prev_direction: Option<Direction>, direction: Direction) -> f32 {
+        let rules = &self.growth_rules;
+
+        let mut weight = match prev_direction {
+            Some(prev_direction) if prev_direction == direction => rules.straight_weight,
+            _ => 1.0,
+        };
+
+        if direction.vector().y != 0 {
+            weight *= rules.vertical_weight;
+        }
+
+        weight
+    }
+}
+
+/// Gets the direction a pipe's head segment was entered moving in, if any.
+fn head_direction(head: Segment) -> Option<Direction> {
+    match head.kind {
+        SegmentKind::Single => None,
+        SegmentKind::End(direction) => Some(direction),
+        kind => unreachable!("Invalid head segment kind: {:?}", kind),
+    }
+}
+
+/// Picks a random direction, with each direction's chance proportional to its
+/// weight. Picks uniformly if no direction has any weight.
+fn choose_weighted<R: Rng>(rand: &mut R, directions: &[(Direction, f32)]) -> Direction {
+    let total: f32 = directions.iter().map(|&(_, weight)| weight).sum();
+
+    if total <= 0.0 {
+        return directions[rand.gen_range(0..directions.len())].0;
+    }
+
+    let mut choice = rand.gen::<f32>() * total;
+    for &(direction, weight) in directions {
+        if choice < weight {
+            return direction;
+        }
+        choice -= weight;
+    }
+
+    // floating point error can leave a tiny bit of choice left over
+    directions
+        .iter()
+        .rev()
+        .find(|&&(_, weight)| weight > 0.0)
+        .unwrap()
+        .0
+}
+
+/// Generates a random location within the bounds of the pipe space.
+fn random_location<R: Rng>(rand: &mut R, size: GridSize) -> Vector3<usize> {
+    Vector3::new(
+        rand.gen_range(0..size.width),
+        rand.gen_range(0..size.height),
+        rand.gen_range(0..size.depth),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        direction::Direction,
+        obstacles::Obstacles,
+        settings::{GridSize, GrowthRules, JointStyle, RollingRules, Settings},
+        simulation::{PipeEvent, PipeSimulation, Segment, SegmentKind, GROWTH_DURATION},
+        spaces::Spaces,
+    };
+    use cgmath::Vector3;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_first_step_starts_pipe() {
+        let mut simulation = PipeSimulation::new(&Settings::default());
+        let mut events = vec![];
+        simulation.step(&mut events);
+
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            PipeEvent::SegmentPlaced(segment) => assert_eq!(segment.kind, SegmentKind::Single),
+            event => panic!("Unexpected first event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_growth_never_overlaps() {
+        assert_growth_never_overlaps(&Settings::default());
+    }
+
+    #[test]
+    fn test_multiple_pipes_never_overlap() {
+        assert_growth_never_overlaps(&Settings {
+            pipe_count: 5,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_multiple_pipes_grow_independently() {
+        let mut simulation = PipeSimulation::new(&Settings {
+            pipe_count: 3,
+            ..Default::default()
+        });
+        let mut events = vec![];
+
+        // the first step starts every pipe
+        simulation.step(&mut events);
+        let starts = events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    PipeEvent::SegmentPlaced(Segment {
+                        kind: SegmentKind::Single,
+                        ..
+                    })
+                )
+            })
+            .count();
+        assert_eq!(starts, 3);
+
+        // less than a growth duration should only grow the pipes whose timers
+        // were already partway through
+        events.clear();
+        simulation.update(GROWTH_DURATION / 2, &mut events);
+        let retyped = events
+            .iter()
+            .filter(|event| matches!(event, PipeEvent::SegmentRetyped { .. }))
+            .count();
+        assert_eq!(retyped, 1);
+    }
+
+    #[test]
+    fn test_same_seed_is_reproducible() {
+        let settings = Settings {
+            pipe_count: 3,
+            seed: Some(42),
+            ..Default::default()
+        };
+        let mut first = PipeSimulation::new(&settings);
+        let mut second = PipeSimulation::new(&settings);
+        let mut first_events = vec![];
+        let mut second_events = vec![];
+
+        for _ in 0..2000 {
+            first.step(&mut first_events);
+            second.step(&mut second_events);
+        }
+
+        assert_eq!(first.seed(), 42);
+        assert_eq!(first_events, second_events);
+    }
+
+    #[test]
+    fn test_different_seeds_differ() {
+        let mut first = PipeSimulation::new(&Settings {
+            seed: Some(1),
+            ..Default::default()
+        });
+        let mut second = PipeSimulation::new(&Settings {
+            seed: Some(2),
+            ..Default::default()
+        });
+        let mut first_events = vec![];
+        let mut second_events = vec![];
+
+        for _ in 0..100 {
+            first.step(&mut first_events);
+            second.step(&mut second_events);
+        }
+
+        assert_ne!(first_events, second_events);
+    }
+
+    #[test]
+    fn test_min_straight_run() {
+        let settings = Settings {
+            seed: Some(5),
+            growth_rules: GrowthRules {
+                min_straight_run: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut simulation = PipeSimulation::new(&settings);
+        let mut events = vec![];
+        let mut occupied = HashSet::new();
+        let mut straight_run = 0;
+
+        for _ in 0..5000 {
+            events.clear();
+            simulation.step(&mut events);
+
+            for event in events.iter() {
+                match *event {
+                    PipeEvent::SegmentPlaced(segment) => {
+                        occupied.insert(segment.location);
+                    }
+                    PipeEvent::SegmentRetyped { old, new } => match (old.kind, new.kind) {
+                        (SegmentKind::End(_), SegmentKind::Straight(_)) => straight_run += 1,
+                        (
+                            SegmentKind::End(direction),
+                            SegmentKind::Bent { .. }
+                            | SegmentKind::BallJoint { .. }
+                            | SegmentKind::Teapot { .. },
+                        ) => {
+                            let blocked = !direction
+                                .is_offset_legal(old.location, settings.grid_size)
+                                || occupied.contains(&direction.offset(old.location));
+                            assert!(
+                                straight_run + 1 >= 4 || blocked,
+                                "Turned after {} straight segments at {:?}",
+                                straight_run + 1,
+                                old.location
+                            );
+                            straight_run = 0;
+                        }
+                        _ => straight_run = 0,
+                    },
+                    PipeEvent::SegmentRemoved(_) => {}
+                    PipeEvent::BoardCleared => occupied.clear(),
+                }
+            }
+        }
+    }
+
+    fn assert_growth_never_overlaps(settings: &Settings) {
+        let mut simulation = PipeSimulation::new(settings);
+        let mut events = vec![];
+        let mut occupied = HashSet::new();
+
+        for _ in 0..10000 {
+            events.clear();
+            simulation.step(&mut events);
+
+            for event in events.iter() {
+                match *event {
+                    PipeEvent::SegmentPlaced(segment) => {
+                        assert!(
+                            occupied.insert(segment.location),
+                            "Segment placed in occupied space: {:?}",
+                            segment
+                        );
+                    }
+                    PipeEvent::SegmentRetyped { old, new } => {
+                        assert_eq!(old.location, new.location);
+                        assert!(occupied.contains(&old.location));
+                    }
+                    PipeEvent::SegmentRemoved(segment) => {
+                        assert!(occupied.remove(&segment.location));
+                    }
+                    PipeEvent::BoardCleared => occupied.clear(),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_growth_connects_to_previous_segment() {
+        let mut simulation = PipeSimulation::new(&Settings::default());
+        let mut events = vec![];
+
+        for _ in 0..1000 {
+            events.clear();
+            simulation.step(&mut events);
+
+            if let [PipeEvent::SegmentRetyped { new, .. }, PipeEvent::SegmentPlaced(placed)] =
+                events[..]
+            {
+                let direction = match placed.kind {
+                    SegmentKind::End(direction) => direction,
+                    kind => panic!("Unexpected placed segment kind: {:?}", kind),
+                };
+                assert_eq!(direction.offset(new.location), placed.location);
+            }
+        }
+    }
+
+    #[test]
+    fn test_certain_teapot_chance_replaces_every_bend() {
+        let mut simulation = PipeSimulation::new(&Settings {
+            teapot_chance: 1.0,
+            ..Default::default()
+        });
+        let mut events = vec![];
+        let mut teapots = 0;
+
+        for _ in 0..1000 {
+            simulation.step(&mut events);
+        }
+
+        for event in events.iter() {
+            if let PipeEvent::SegmentRetyped { new, .. } = event {
+                match new.kind {
+                    SegmentKind::Bent { .. } => panic!("Bend not replaced: {:?}", new),
+                    SegmentKind::Teapot { .. } => teapots += 1,
+                    _ => {}
+                }
+            }
+        }
+
+        assert!(teapots > 0);
+    }
+
+    #[test]
+    fn test_mixed_joint_style_is_per_pipe() {
+        let mut simulation = PipeSimulation::new(&Settings {
+            seed: Some(3),
+            teapot_chance: 0.0,
+            joint_style: JointStyle::Mixed,
+            ..Default::default()
+        });
+        let mut events = vec![];
+        let mut pipe_joints = vec![];
+
+        for _ in 0..5000 {
+            simulation.step(&mut events);
+        }
+
+        for event in events.iter() {
+            match *event {
+                PipeEvent::SegmentPlaced(Segment {
+                    kind: SegmentKind::Single,
+                    ..
+                }) => pipe_joints.push(None),
+                PipeEvent::SegmentRetyped { new, .. } => {
+                    let ball = match new.kind {
+                        SegmentKind::Bent { .. } => false,
+                        SegmentKind::BallJoint { .. } => true,
+                        _ => continue,
+                    };
+
+                    let joints = pipe_joints.last_mut().unwrap();
+                    assert_eq!(*joints.get_or_insert(ball), ball, "Pipe mixed joint styles");
+                }
+                _ => {}
+            }
+        }
+
+        assert!(pipe_joints.contains(&Some(false)));
+        assert!(pipe_joints.contains(&Some(true)));
+    }
+
+    #[test]
+    fn test_growth_stays_in_non_cubic_grid() {
+        let grid_size = GridSize::new(6, 2, 3);
+        let mut simulation = PipeSimulation::new(&Settings {
+            grid_size,
+            ..Default::default()
+        });
+        let mut events = vec![];
+
+        for _ in 0..1000 {
+            events.clear();
+            simulation.step(&mut events);
+
+            for event in events.iter() {
+                if let PipeEvent::SegmentPlaced(segment) = event {
+                    assert!(
+                        grid_size.contains(segment.location),
+                        "Segment placed out of bounds: {:?}",
+                        segment
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_lookahead_grows_into_larger_pocket() {
+        for seed in 0..20 {
+            let mut simulation = PipeSimulation::new(&Settings {
+                grid_size: GridSize::new(7, 1, 1),
+                seed: Some(seed),
+                growth_rules: GrowthRules {
+                    lookahead: 10,
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+            let mut events = vec![];
+            simulation.step(&mut events);
+            simulation.step(&mut events);
+
+            let placed: Vec<_> = events
+                .iter()
+                .filter_map(|event| match event {
+                    PipeEvent::SegmentPlaced(segment) => Some(segment.location.x),
+                    _ => None,
+                })
+                .collect();
+
+            // the pipe should head towards whichever end of the grid is further
+            let (start, next) = (placed[0], placed[1]);
+            if start < 3 {
+                assert_eq!(next, start + 1);
+            } else if start > 3 {
+                assert_eq!(next, start - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_growth_avoids_obstacles() {
+        let grid_size = GridSize::new(8, 8, 8);
+        let mut simulation = PipeSimulation::new(&Settings {
+            grid_size,
+            pipe_count: 3,
+            obstacles: Obstacles::HollowBox,
+            ..Default::default()
+        });
+        let obstacles: HashSet<_> = simulation.obstacles().collect();
+        assert!(!obstacles.is_empty());
+
+        let mut events = vec![];
+        for _ in 0..2000 {
+            simulation.step(&mut events);
+        }
+
+        assert!(events.contains(&PipeEvent::BoardCleared));
+        for event in events.iter() {
+            if let PipeEvent::SegmentPlaced(segment) = event {
+                assert!(
+                    !obstacles.contains(&segment.location),
+                    "Segment placed in obstacle: {:?}",
+                    segment
+                );
+            }
+        }
+        assert_eq!(simulation.obstacles().count(), obstacles.len());
+    }
+
+    #[test]
+    fn test_spaces_track_pipe_ownership() {
+        let mut simulation = PipeSimulation::new(&Settings {
+            grid_size: GridSize::new(6, 6, 6),
+            pipe_count: 3,
+            obstacles: Obstacles::Pillars,
+            seed: Some(11),
+            ..Default::default()
+        });
+        let mut events = vec![];
+
+        for _ in 0..60 {
+            simulation.step(&mut events);
+        }
+
+        let spaces = simulation.spaces();
+        let pipes: HashSet<_> = spaces.segments().map(|segment| segment.pipe).collect();
+        assert_eq!(pipes.len(), 3);
+        assert_eq!(spaces.pipe_count(), 3);
+
+        // pipes are indexed from oldest to newest
+        let indexed: Vec<_> = spaces.pipes().collect();
+        assert!(indexed.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(indexed.iter().copied().collect::<HashSet<_>>(), pipes);
+
+        for pipe in pipes {
+            // every segment is entered from another segment of the same pipe
+            for segment in spaces.pipe_segments(pipe) {
+                if let Some(entry) = segment.kind.entry() {
+                    let from = spaces
+                        .neighbor(segment.location, entry.opposite())
+                        .and_then(|location| spaces.segment(location))
+                        .unwrap();
+                    assert_eq!(from.pipe, pipe);
+                    assert_eq!(from.kind.exit(), Some(entry));
+                }
+            }
+        }
+
+        let obstacles = spaces.obstacles().count();
+        assert_eq!(spaces.occupied_count(), spaces.segments().count());
+        assert_eq!(
+            spaces.free_spaces().count() + spaces.occupied_count() + obstacles,
+            6 * 6 * 6
+        );
+        assert_eq!(
+            spaces.fill_fraction(),
+            spaces.occupied_count() as f32 / (6 * 6 * 6 - obstacles) as f32
+        );
+    }
+
+    #[test]
+    fn test_remove_oldest_pipe() {
+        let mut simulation = PipeSimulation::new(&Settings {
+            pipe_count: 3,
+            seed: Some(8),
+            ..Default::default()
+        });
+        let mut events = vec![];
+
+        for _ in 0..100 {
+            simulation.step(&mut events);
+        }
+
+        let oldest = simulation.oldest_pipe().unwrap();
+        let length = simulation.spaces().pipe_segments(oldest).count();
+        let occupied = simulation.spaces().occupied_count();
+
+        events.clear();
+        assert!(simulation.remove_pipe(oldest, &mut events));
+        assert_eq!(events.len(), length);
+        assert!(events.iter().all(
+            |event| matches!(event, PipeEvent::SegmentRemoved(segment) if segment.pipe == oldest)
+        ));
+        assert_eq!(simulation.spaces().pipe_segments(oldest).count(), 0);
+        assert_eq!(simulation.spaces().occupied_count(), occupied - length);
+        assert!(!simulation.remove_pipe(oldest, &mut events));
+        assert_ne!(simulation.oldest_pipe(), Some(oldest));
+    }
+
+    #[test]
+    fn test_rolling_retires_oldest_pipes() {
+        let mut simulation = PipeSimulation::new(&Settings {
+            grid_size: GridSize::new(6, 6, 6),
+            pipe_count: 2,
+            seed: Some(3),
+            rolling: Some(RollingRules {
+                max_pipes: Some(4),
+                max_fill: 0.5,
+            }),
+            ..Default::default()
+        });
+        let mut events = vec![];
+
+        for _ in 0..2000 {
+            simulation.step(&mut events);
+            let pipes: HashSet<_> = simulation.segments().map(|segment| segment.pipe).collect();
+            assert!(pipes.len() <= 4);
+        }
+
+        assert!(!events.contains(&PipeEvent::BoardCleared));
+
+        // finished pipes are retired strictly oldest first
+        let newest_removed = events
+            .iter()
+            .filter_map(|event| match event {
+                PipeEvent::SegmentRemoved(segment) => Some(segment.pipe),
+                _ => None,
+            })
+            .max()
+            .unwrap();
+        assert!(simulation.oldest_finished_pipe().unwrap() > newest_removed);
+    }
+
+    #[test]
+    fn test_rolling_never_retires_growing_pipes() {
+        // with no room for finished pipes at all, only growing pipes are left
+        let mut simulation = PipeSimulation::new(&Settings {
+            grid_size: GridSize::new(6, 6, 6),
+            pipe_count: 2,
+            seed: Some(5),
+            rolling: Some(RollingRules {
+                max_pipes: Some(2),
+                max_fill: 0.0,
+            }),
+            ..Default::default()
+        });
+        let mut events = vec![];
+        let mut longest = 0;
+
+        for _ in 0..500 {
+            simulation.step(&mut events);
+
+            let growing: Vec<_> = simulation
+                .pipes
+                .iter()
+                .filter_map(|slot| slot.head)
+                .map(|head| head.pipe)
+                .collect();
+            assert_eq!(growing.len(), 2);
+
+            events.clear();
+            simulation.retire_pipes(&mut events);
+            assert!(events.is_empty());
+
+            for pipe in growing {
+                longest = longest.max(simulation.spaces().pipe_segments(pipe).count());
+            }
+        }
+
+        assert!(longest > 1);
+    }
+
+    #[test]
+    fn test_pipes_wrap_around_edges() {
+        let settings = Settings {
+            grid_size: GridSize::new(4, 4, 4),
+            wrap: true,
+            seed: Some(3),
+            ..Default::default()
+        };
+        assert_growth_never_overlaps(&settings);
+
+        let mut simulation = PipeSimulation::new(&settings);
+        let mut events = vec![];
+        let mut wraps = 0;
+
+        for _ in 0..1000 {
+            events.clear();
+            simulation.step(&mut events);
+
+            if let [PipeEvent::SegmentRetyped { old, .. }, PipeEvent::SegmentPlaced(placed)] =
+                events[..]
+            {
+                let SegmentKind::End(direction) = placed.kind else {
+                    panic!("Unexpected placed segment: {:?}", placed);
+                };
+                if !direction.is_offset_legal(old.location, settings.grid_size) {
+                    assert_eq!(
+                        placed.location,
+                        direction.wrapping_offset(old.location, settings.grid_size)
+                    );
+                    wraps += 1;
+                }
+            }
+        }
+
+        assert!(wraps > 0);
+    }
+
+    #[test]
+    fn test_wrapping_across_thin_grid_never_returns_to_start() {
+        // wrapping along an axis only one space long would end up right back
+        // where the move started
+        let spaces = Spaces::new(GridSize::new(7, 1, 1), true);
+        assert_eq!(spaces.neighbor(Vector3::new(3, 0, 0), Direction::Up), None);
+        assert_eq!(
+            spaces.neighbor(Vector3::new(3, 0, 0), Direction::UpEast),
+            None
+        );
+        assert_eq!(
+            spaces.neighbor(Vector3::new(6, 0, 0), Direction::East),
+            Some(Vector3::new(0, 0, 0))
+        );
+
+        for diagonal in [false, true] {
+            assert_growth_never_overlaps(&Settings {
+                grid_size: GridSize::new(7, 1, 1),
+                wrap: true,
+                growth_rules: GrowthRules {
+                    diagonal,
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid grid size")]
+    fn test_rejects_grid_past_limit() {
+        // sizes built directly skip GridSize::new, but not the simulation
+        PipeSimulation::new(&Settings {
+            grid_size: GridSize {
+                width: GridSize::MAX_LENGTH + 1,
+                height: 1,
+                depth: 1,
+            },
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_diagonal_pipes_never_cross() {
+        let mut simulation = PipeSimulation::new(&Settings {
+            grid_size: GridSize::new(6, 6, 6),
+            pipe_count: 4,
+            growth_rules: GrowthRules {
+                diagonal: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut events = vec![];
+        let mut midpoints = HashSet::new();
+        let mut diagonal_moves = 0;
+
+        for _ in 0..3000 {
+            events.clear();
+            simulation.step(&mut events);
+
+            for event in events.iter() {
+                match *event {
+                    PipeEvent::BoardCleared => midpoints.clear(),
+                    PipeEvent::SegmentPlaced(Segment {
+                        location,
+                        kind: SegmentKind::End(direction),
+                        ..
+                    }) => {
+                        // crossing pipes would pass through the same point
+                        // between their spaces
+                        let from = location.cast::<i32>().unwrap() - direction.vector();
+                        let midpoint = location.cast::<i32>().unwrap() + from;
+                        assert!(
+                            midpoints.insert(midpoint),
+                            "Pipes crossed at {:?}",
+                            midpoint
+                        );
+
+                        if direction.is_diagonal() {
+                            diagonal_moves += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        assert!(diagonal_moves > 0);
+    }
+}
@@ -0,0 +1,817 @@
+use crate::{
+    color::{hex_color, parse_hex_color, ColorGradient, ColorPicker, ColorScheme, Palette},
+    direction::Direction,
+    recording::parse_word,
+    settings::{GridSize, GrowthRules, JointStyle, RollingRules},
+    simulation::{Pipe, PipeId, Segment, SegmentKind, SimulationSnapshot},
+    spaces::Spaces,
+};
+use cgmath::Vector3;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter},
+    io,
+    io::{BufRead, Write},
+    str::SplitWhitespace,
+    time::Duration,
+};
+
+/// The first line of every snapshot file.
+const MAGIC: &str = "kpipes-snapshot 1";
+
+/// The complete state of a KPipes scene, which can be saved and restored to
+/// continue exactly where it left off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub(crate) simulation: SimulationSnapshot,
+    /// The camera's rotation around the grid.
+    pub(crate) camera_rotation: f32,
+}
+
+impl Snapshot {
+    /// Reads a snapshot written by [`write`](Snapshot::write).
+    pub fn read<R: BufRead>(reader: R) -> Result<Snapshot, SnapshotError> {
+        let mut lines = reader.lines().enumerate();
+        let mut next_line = || -> Result<Option<(usize, String)>, SnapshotError> {
+            match lines.next() {
+                Some((index, line)) => Ok(Some((index + 1, line?))),
+                None => Ok(None),
+            }
+        };
+
+        match next_line()? {
+            Some((_, line)) if line.trim() == MAGIC => {}
+            _ => return Err(SnapshotError::MissingHeader),
+        }
+
+        let mut header = |name: &str| -> Result<(usize, String), SnapshotError> {
+            let (line_number, line) = next_line()?.ok_or(SnapshotError::MissingHeader)?;
+            match line
+                .strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix(' '))
+            {
+                Some(value) => Ok((line_number, value.trim().to_string())),
+                None => Err(SnapshotError::InvalidLine(line_number, line)),
+            }
+        };
+
+        let grid_size = parse_header(header("grid")?, GridSize::parse)?;
+        let wrap = parse_header(header("wrap")?, |value| value.parse().ok())?;
+        let growth_rules = parse_header(header("rules")?, |value| {
+            let mut words = value.split_whitespace();
+            let rules = GrowthRules {
+                straight_weight: parse_word(&mut words)?,
+                min_straight_run: parse_word(&mut words)?,
+                vertical_weight: parse_word(&mut words)?,
+                lookahead: parse_word(&mut words)?,
+                diagonal: parse_word(&mut words)?,
+            };
+            let valid = [rules.straight_weight, rules.vertical_weight]
+                .iter()
+                .all(|weight| weight.is_finite() && *weight >= 0.0);
+            (valid && words.next().is_none()).then_some(rules)
+        })?;
+        let teapot_chance = parse_header(header("teapot-chance")?, |value| {
+            value
+                .parse()
+                .ok()
+                .filter(|chance| (0.0..=1.0).contains(chance))
+        })?;
+        let joint_style = parse_header(header("joints")?, JointStyle::from_name)?;
+        let colors = parse_header(header("colors")?, parse_color_picker)?;
+        let color_gradient = parse_header(header("gradient")?, ColorGradient::parse)?;
+        let rolling = parse_header(header("rolling")?, parse_rolling)?;
+        let seed = parse_header(header("seed")?, |value| value.parse().ok())?;
+        let (rand_seed, rand_stream, rand_word_pos) = parse_header(header("rng")?, |value| {
+            let mut words = value.split_whitespace();
+            let rand_seed = parse_hex_seed(words.next()?)?;
+            let rand_stream = parse_word(&mut words)?;
+            let rand_word_pos = parse_word(&mut words)?;
+            words
+                .next()
+                .is_none()
+                .then_some((rand_seed, rand_stream, rand_word_pos))
+        })?;
+        let time = Duration::from_nanos(parse_header(header("time")?, |value| value.parse().ok())?);
+        let next_pipe_id = parse_header(header("next-pipe")?, |value| value.parse().ok())?;
+        let camera_rotation = parse_header(header("camera")?, |value| {
+            value
+                .parse()
+                .ok()
+                .filter(|rotation: &f32| rotation.is_finite())
+        })?;
+
+        // pipes refer to their heads by location, so they are only resolved
+        // once every segment has been read
+        let mut pipes = vec![];
+        let mut segments = vec![];
+        let mut segment_lines = vec![];
+        let mut segment_indices = HashMap::new();
+        let mut obstacles = HashSet::new();
+
+        while let Some((line_number, line)) = next_line()? {
+            let mut words = line.split_whitespace();
+            let parsed = match words.next() {
+                None => continue,
+                Some("pipe") => parse_pipe(&mut words).map(|pipe| pipes.push((line_number, pipe))),
+                Some("segment") => parse_segment(&mut words)
+                    .filter(|segment| {
+                        segment.pipe.0 < next_pipe_id
+                            && grid_size.contains(segment.location)
+                            && !segment_indices.contains_key(&segment.location)
+                            && !obstacles.contains(&segment.location)
+                    })
+                    .map(|segment| {
+                        segment_indices.insert(segment.location, segments.len());
+                        segments.push(segment);
+                        segment_lines.push(line_number);
+                    }),
+                Some("obstacle") => parse_location(&mut words)
+                    .filter(|&location| {
+                        grid_size.contains(location) && !segment_indices.contains_key(&location)
+                    })
+                    .and_then(|location| obstacles.insert(location).then_some(())),
+                Some(_) => None,
+            };
+
+            if parsed.is_none() || words.next().is_some() {
+                return Err(SnapshotError::InvalidLine(line_number, line));
+            }
+        }
+
+        if pipes.is_empty() {
+            return Err(SnapshotError::NoPipes);
+        }
+
+        // new pipes are started in a free space, so there has to be one
+        if obstacles.len() + segments.len() >= grid_size.volume() {
+            return Err(SnapshotError::FullGrid);
+        }
+
+        // each pipe's segments are in the order they were placed, each one
+        // entered the way the one before it was left
+        let spaces = Spaces::new(grid_size, wrap);
+        let mut pipe_ends: HashMap<PipeId, (Segment, usize, u32)> = HashMap::new();
+        for (&segment, &line_number) in segments.iter().zip(segment_lines.iter()) {
+            let connected = match (pipe_ends.get(&segment.pipe), segment.kind.entry()) {
+                (None, None) => true,
+                (Some((prev, _, _)), Some(entry)) => {
+                    prev.kind.exit() == Some(entry)
+                        && spaces.neighbor(prev.location, entry) == Some(segment.location)
+                }
+                _ => false,
+            };
+            if !connected {
+                return Err(SnapshotError::DisconnectedSegment(line_number));
+            }
+
+            let count = pipe_ends
+                .get(&segment.pipe)
+                .map_or(0, |&(_, _, count)| count);
+            pipe_ends.insert(segment.pipe, (segment, line_number, count + 1));
+        }
+
+        // every pipe ends in a segment that leads nowhere
+        if let Some(&(_, line_number, _)) = pipe_ends
+            .values()
+            .filter(|(end, _, _)| end.kind.exit().is_some())
+            .min_by_key(|&&(_, line_number, _)| line_number)
+        {
+            return Err(SnapshotError::DisconnectedSegment(line_number));
+        }
+
+        let mut growing = HashSet::new();
+        let pipes = pipes
+            .into_iter()
+            .map(|(line_number, (mut pipe, head))| {
+                if let Some(location) = head {
+                    match segment_indices.get(&location).map(|&index| segments[index]) {
+                        Some(segment)
+                            if matches!(
+                                segment.kind,
+                                SegmentKind::Single | SegmentKind::End(_)
+                            ) =>
+                        {
+                            pipe.head = Some(segment);
+                        }
+                        _ => return Err(SnapshotError::InvalidHead(line_number)),
+                    }
+                }
+
+                if let Some(head) = pipe.head {
+                    if !growing.insert(head.pipe) {
+                        return Err(SnapshotError::SharedHead(line_number));
+                    }
+
+                    // a head at the end of some other pipe gives it away
+                    let (_, _, count) = pipe_ends[&head.pipe];
+                    if count != pipe.length + 1 {
+                        return Err(SnapshotError::ForeignHead(line_number));
+                    }
+                }
+
+                Ok(pipe)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // growing pipes are never retired, so they all have to fit
+        if let Some(max_pipes) = rolling.and_then(|rules| rules.max_pipes) {
+            if max_pipes < pipes.len() {
+                return Err(SnapshotError::TooFewMaxPipes(max_pipes, pipes.len()));
+            }
+        }
+
+        let mut obstacles: Vec<_> = obstacles.into_iter().collect();
+        obstacles.sort_by_key(|location| (location.z, location.y, location.x));
+
+        Ok(Snapshot {
+            simulation: SimulationSnapshot {
+                grid_size,
+                wrap,
+                growth_rules,
+                teapot_chance,
+                joint_style,
+                colors,
+                color_gradient,
+                rolling,
+                seed,
+                rand_seed,
+                rand_stream,
+                rand_word_pos,
+                time,
+                pipes,
+                next_pipe_id,
+                segments,
+                obstacles,
+            },
+            camera_rotation,
+        })
+    }
+
+    /// Writes this snapshot.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let simulation = &self.simulation;
+        let rules = &simulation.growth_rules;
+
+        writeln!(writer, "{}", MAGIC)?;
+        writeln!(writer, "grid {}", simulation.grid_size)?;
+        writeln!(writer, "wrap {}", simulation.wrap)?;
+        writeln!(
+            writer,
+            "rules {} {} {} {} {}",
+            rules.straight_weight,
+            rules.min_straight_run,
+            rules.vertical_weight,
+            rules.lookahead,
+            rules.diagonal
+        )?;
+        writeln!(writer, "teapot-chance {}", simulation.teapot_chance)?;
+        writeln!(writer, "joints {}", simulation.joint_style.name())?;
+        write_color_picker(&mut writer, &simulation.colors)?;
+        writeln!(writer, "gradient {}", simulation.color_gradient)?;
+        match simulation.rolling {
+            Some(rules) => match rules.max_pipes {
+                Some(max_pipes) => writeln!(writer, "rolling {} {}", max_pipes, rules.max_fill)?,
+                None => writeln!(writer, "rolling none {}", rules.max_fill)?,
+            },
+            None => writeln!(writer, "rolling none")?,
+        }
+        writeln!(writer, "seed {}", simulation.seed)?;
+        write!(writer, "rng ")?;
+        for byte in simulation.rand_seed {
+            write!(writer, "{:02x}", byte)?;
+        }
+        writeln!(
+            writer,
+            " {} {}",
+            simulation.rand_stream, simulation.rand_word_pos
+        )?;
+        writeln!(writer, "time {}", simulation.time.as_nanos())?;
+        writeln!(writer, "next-pipe {}", simulation.next_pipe_id)?;
+        writeln!(writer, "camera {}", self.camera_rotation)?;
+
+        for pipe in simulation.pipes.iter() {
+            write!(
+                writer,
+                "pipe {} {} {} {} {} {} {}",
+                pipe.color.x,
+                pipe.color.y,
+                pipe.color.z,
+                pipe.straight_run,
+                pipe.length,
+                pipe.joint_style.name(),
+                pipe.time_since_growth.as_nanos()
+            )?;
+            match pipe.head {
+                Some(head) => writeln!(
+                    writer,
+                    " {} {} {}",
+                    head.location.x, head.location.y, head.location.z
+                )?,
+                None => writeln!(writer, " none")?,
+            }
+        }
+
+        for location in simulation.obstacles.iter() {
+            writeln!(
+                writer,
+                "obstacle {} {} {}",
+                location.x, location.y, location.z
+            )?;
+        }
+
+        for segment in simulation.segments.iter() {
+            write!(
+                writer,
+                "segment {} {} {} {} {} {} {}",
+                segment.location.x,
+                segment.location.y,
+                segment.location.z,
+                segment.color.x,
+                segment.color.y,
+                segment.color.z,
+                segment.pipe
+            )?;
+            match segment.kind {
+                SegmentKind::Single => writeln!(writer, " single")?,
+                SegmentKind::Start(direction) => writeln!(writer, " start {}", direction.name())?,
+                SegmentKind::Straight(direction) => {
+                    writeln!(writer, " straight {}", direction.name())?
+                }
+                SegmentKind::Bent { from, to } => {
+                    writeln!(writer, " bent {} {}", from.name(), to.name())?
+                }
+                SegmentKind::End(direction) => writeln!(writer, " end {}", direction.name())?,
+                SegmentKind::Teapot { from, to } => {
+                    writeln!(writer, " teapot {} {}", from.name(), to.name())?
+                }
+                SegmentKind::BallJoint { from, to } => {
+                    writeln!(writer, " ball {} {}", from.name(), to.name())?
+                }
+            }
+        }
+
+        writer.flush()
+    }
+}
+
+/// Parses the value of a header line.
+fn parse_header<T, F: FnOnce(&str) -> Option<T>>(
+    (line_number, value): (usize, String),
+    parse: F,
+) -> Result<T, SnapshotError> {
+    parse(&value).ok_or(SnapshotError::InvalidLine(line_number, value))
+}
+
+/// Parses rolling limits, written as the most pipes allowed (or `none`) and
+/// the largest fill fraction, or as just `none` if the board is cleared
+/// instead.
+fn parse_rolling(value: &str) -> Option<Option<RollingRules>> {
+    let mut words = value.split_whitespace();
+    let max_pipes = match words.next()? {
+        "none" => None,
+        max_pipes => Some(max_pipes.parse().ok().filter(|&max_pipes| max_pipes > 0)?),
+    };
+
+    let Some(max_fill) = words.next() else {
+        return max_pipes.is_none().then_some(None);
+    };
+    let max_fill = max_fill
+        .parse()
+        .ok()
+        .filter(|max_fill| (0.0..=1.0).contains(max_fill))?;
+
+    words.next().is_none().then_some(Some(RollingRules {
+        max_pipes,
+        max_fill,
+    }))
+}
+
+/// Parses a 32 byte RNG seed written as hex.
+fn parse_hex_seed(value: &str) -> Option<[u8; 32]> {
+    if value.len() != 64 || !value.is_ascii() {
+        return None;
+    }
+
+    let mut seed = [0; 32];
+    for (index, byte) in seed.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[index * 2..index * 2 + 2], 16).ok()?;
+    }
+
+    Some(seed)
+}
+
+/// Parses a color picker, written as how many colors it has picked, the hue
+/// of the last analogous color and then its color scheme.
+fn parse_color_picker(value: &str) -> Option<ColorPicker> {
+    let mut words = value.split_whitespace();
+    let picked = parse_word(&mut words)?;
+    let hue = match words.next()? {
+        "none" => None,
+        hue => Some(hue.parse().ok().filter(|hue| (0.0..1.0).contains(hue))?),
+    };
+
+    let scheme = match ColorScheme::from_name(words.next()?)? {
+        ColorScheme::Monochrome(_) => {
+            ColorScheme::Monochrome(parse_word(&mut words).filter(|hue: &f32| hue.is_finite())?)
+        }
+        scheme @ (ColorScheme::Palette(_) | ColorScheme::Cycle(_)) => {
+            let colors = words.by_ref().map(parse_hex_color).collect::<Option<_>>()?;
+            scheme.with_palette(Palette::new(colors)?)
+        }
+        scheme => scheme,
+    };
+
+    words.next().is_none().then_some(ColorPicker {
+        scheme,
+        picked,
+        hue,
+    })
+}
+
+/// Writes a color picker's header line.
+fn write_color_picker<W: Write>(writer: &mut W, colors: &ColorPicker) -> io::Result<()> {
+    write!(writer, "colors {}", colors.picked)?;
+    match colors.hue {
+        Some(hue) => write!(writer, " {}", hue)?,
+        None => write!(writer, " none")?,
+    }
+    write!(writer, " {}", colors.scheme.name())?;
+
+    if let ColorScheme::Monochrome(hue) = colors.scheme {
+        write!(writer, " {}", hue)?;
+    }
+    for &color in colors
+        .scheme
+        .palette()
+        .map_or(&[][..], |palette| palette.colors())
+    {
+        write!(writer, " {}", hex_color(color))?;
+    }
+
+    writeln!(writer)
+}
+
+/// Parses a pipe along with the location of its head, if it has one.
+fn parse_pipe(words: &mut SplitWhitespace) -> Option<(Pipe, Option<Vector3<usize>>)> {
+    let color = Vector3::new(parse_word(words)?, parse_word(words)?, parse_word(words)?);
+    let straight_run = parse_word(words)?;
+    let length = parse_word(words)?;
+    let joint_style =
+        JointStyle::from_name(words.next()?).filter(|&style| style != JointStyle::Mixed)?;
+    let time_since_growth = Duration::from_nanos(parse_word(words)?);
+
+    let head = match words.next()? {
+        "none" => None,
+        x => Some(Vector3::new(
+            x.parse().ok()?,
+            parse_word(words)?,
+            parse_word(words)?,
+        )),
+    };
+
+    Some((
+        Pipe {
+            color,
+            head: None,
+            straight_run,
+            length,
+            joint_style,
+            time_since_growth,
+        },
+        head,
+    ))
+}
+
+/// Parses a location written as its x, y and z coordinates.
+fn parse_location(words: &mut SplitWhitespace) -> Option<Vector3<usize>> {
+    Some(Vector3::new(
+        parse_word(words)?,
+        parse_word(words)?,
+        parse_word(words)?,
+    ))
+}
+
+/// Parses a segment.
+fn parse_segment(words: &mut SplitWhitespace) -> Option<Segment> {
+    let location = parse_location(words)?;
+    let color = Vector3::new(parse_word(words)?, parse_word(words)?, parse_word(words)?);
+    let pipe = PipeId(parse_word(words)?);
+
+    let kind = match words.next()? {
+        "single" => SegmentKind::Single,
+        "start" => SegmentKind::Start(parse_direction(words)?),
+        "straight" => SegmentKind::Straight(parse_direction(words)?),
+        "end" => SegmentKind::End(parse_direction(words)?),
+        turn => {
+            let from = parse_direction(words)?;
+            let to = parse_direction(words)?;
+
+            // turns can only be oriented if they actually turn
+            if from == to || from.is_opposite(to) {
+                return None;
+            }
+
+            match turn {
+                "bent" => SegmentKind::Bent { from, to },
+                "teapot" => SegmentKind::Teapot { from, to },
+                "ball" => SegmentKind::BallJoint { from, to },
+                _ => return None,
+            }
+        }
+    };
+
+    Some(Segment {
+        location,
+        kind,
+        color,
+        pipe,
+    })
+}
+
+/// Parses the next word of a line as a direction.
+fn parse_direction(words: &mut SplitWhitespace) -> Option<Direction> {
+    Direction::from_name(words.next()?)
+}
+
+/// Error potentially returned when reading a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+    IOError(io::Error),
+    MissingHeader,
+    InvalidLine(usize, String),
+    InvalidHead(usize),
+    SharedHead(usize),
+    ForeignHead(usize),
+    DisconnectedSegment(usize),
+    NoPipes,
+    FullGrid,
+    TooFewMaxPipes(usize, usize),
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::IOError(e)
+    }
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::IOError(e) => write!(f, "Error reading snapshot: {}", e),
+            SnapshotError::MissingHeader => write!(f, "Not a KPipes snapshot"),
+            SnapshotError::InvalidLine(line_number, line) => {
+                write!(f, "Invalid snapshot line {}: {}", line_number, line)
+            }
+            SnapshotError::InvalidHead(line_number) => write!(
+                f,
+                "Head of pipe on line {} isn't the end of a pipe",
+                line_number
+            ),
+            SnapshotError::SharedHead(line_number) => write!(
+                f,
+                "Head of pipe on line {} is already growing another pipe",
+                line_number
+            ),
+            SnapshotError::ForeignHead(line_number) => write!(
+                f,
+                "Head of pipe on line {} belongs to a pipe of a different length",
+                line_number
+            ),
+            SnapshotError::DisconnectedSegment(line_number) => write!(
+                f,
+                "Segment on line {} doesn't join up with the rest of its pipe",
+                line_number
+            ),
+            SnapshotError::NoPipes => write!(f, "Snapshot has no pipes"),
+            SnapshotError::FullGrid => write!(f, "Snapshot has no free space left"),
+            SnapshotError::TooFewMaxPipes(max_pipes, pipe_count) => write!(
+                f,
+                "Snapshot allows {} pipes on the board, fewer than its {} growing pipes",
+                max_pipes, pipe_count
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        color::{ColorGradient, ColorScheme},
+        obstacles::Obstacles,
+        settings::{JointStyle, RollingRules, Settings},
+        simulation::PipeSimulation,
+        snapshot::{Snapshot, SnapshotError},
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_restored_simulation_continues_identically() {
+        let mut simulation = PipeSimulation::new(&Settings {
+            pipe_count: 3,
+            teapot_chance: 0.1,
+            joint_style: JointStyle::Mixed,
+            color_scheme: ColorScheme::Analogous,
+            color_gradient: ColorGradient::HueDrift(7.5),
+            rolling: Some(RollingRules {
+                max_pipes: Some(5),
+                max_fill: 0.3,
+            }),
+            obstacles: Obstacles::Pillars,
+            ..Default::default()
+        });
+        let mut events = vec![];
+
+        for frame in 0..1000 {
+            simulation.update(Duration::from_millis(frame % 40), &mut events);
+        }
+
+        let snapshot = Snapshot {
+            simulation: simulation.snapshot(),
+            camera_rotation: 1.5,
+        };
+        let mut file = vec![];
+        snapshot.write(&mut file).unwrap();
+        assert!(!snapshot.simulation.obstacles.is_empty());
+        let read = Snapshot::read(&file[..]).unwrap();
+        assert_eq!(snapshot, read);
+
+        let mut restored = PipeSimulation::restore(&read.simulation);
+        let mut expected_events = vec![];
+        let mut restored_events = vec![];
+
+        for frame in 0..2000 {
+            simulation.update(Duration::from_millis(frame % 40), &mut expected_events);
+            restored.update(Duration::from_millis(frame % 40), &mut restored_events);
+        }
+
+        assert_eq!(expected_events, restored_events);
+    }
+
+    #[test]
+    fn test_rejects_head_in_middle_of_pipe() {
+        let file = "kpipes-snapshot 1
+grid 4x4x4
+wrap false
+rules 1 0 1 0 false
+teapot-chance 0
+joints mitred
+colors 0 none random
+gradient none
+rolling none
+seed 0
+rng 0000000000000000000000000000000000000000000000000000000000000000 0 0
+time 0
+next-pipe 1
+camera 0
+pipe 1 0 0 0 0 mitred 0 1 1 1
+segment 1 1 1 1 0 0 0 start up
+segment 1 2 1 1 0 0 0 end up
+";
+
+        assert!(matches!(
+            Snapshot::read(file.as_bytes()),
+            Err(SnapshotError::InvalidHead(15))
+        ));
+    }
+
+    /// Writes a snapshot file of a grid of the given size, with the given
+    /// pipe, segment and obstacle lines starting on line 15.
+    fn snapshot_file(grid: &str, lines: &str) -> String {
+        format!(
+            "kpipes-snapshot 1
+grid {}
+wrap false
+rules 1 0 1 0 false
+teapot-chance 0
+joints mitred
+colors 0 none random
+gradient none
+rolling none
+seed 0
+rng 0000000000000000000000000000000000000000000000000000000000000000 0 0
+time 0
+next-pipe 2
+camera 0
+{}",
+            grid, lines
+        )
+    }
+
+    #[test]
+    fn test_rejects_heads_shared_by_pipes() {
+        let file = snapshot_file(
+            "4x4x4",
+            "pipe 1 0 0 0 1 mitred 0 1 2 1
+pipe 0 1 0 0 1 mitred 0 1 2 1
+segment 1 1 1 1 0 0 0 start up
+segment 1 2 1 1 0 0 0 end up
+",
+        );
+
+        assert!(matches!(
+            Snapshot::read(file.as_bytes()),
+            Err(SnapshotError::SharedHead(16))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_head_of_another_pipe() {
+        // the pipe has only just started, but its head ends a longer pipe
+        let file = snapshot_file(
+            "4x4x4",
+            "pipe 1 0 0 0 0 mitred 0 1 2 1
+segment 1 1 1 1 0 0 0 start up
+segment 1 2 1 1 0 0 0 end up
+",
+        );
+
+        assert!(matches!(
+            Snapshot::read(file.as_bytes()),
+            Err(SnapshotError::ForeignHead(15))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_disconnected_segments() {
+        // entered moving east, but the segment before it was left moving up
+        let turned = snapshot_file(
+            "4x4x4",
+            "pipe 1 0 0 0 1 mitred 0 1 2 1
+segment 1 1 1 1 0 0 0 start up
+segment 1 2 1 1 0 0 0 end east
+",
+        );
+        assert!(matches!(
+            Snapshot::read(turned.as_bytes()),
+            Err(SnapshotError::DisconnectedSegment(17))
+        ));
+
+        // entered the right way, but not next to the segment before it
+        let apart = snapshot_file(
+            "4x4x4",
+            "pipe 1 0 0 0 1 mitred 0 1 3 1
+segment 1 1 1 1 0 0 0 start up
+segment 1 3 1 1 0 0 0 end up
+",
+        );
+        assert!(matches!(
+            Snapshot::read(apart.as_bytes()),
+            Err(SnapshotError::DisconnectedSegment(17))
+        ));
+
+        // pipes can't end partway through a straight
+        let unfinished = snapshot_file(
+            "4x4x4",
+            "pipe 1 0 0 0 0 mitred 0 none
+segment 1 1 1 1 0 0 0 start up
+segment 1 2 1 1 0 0 0 straight up
+",
+        );
+        assert!(matches!(
+            Snapshot::read(unfinished.as_bytes()),
+            Err(SnapshotError::DisconnectedSegment(17))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_full_grid() {
+        let file = snapshot_file(
+            "2x2x1",
+            "pipe 1 0 0 0 0 mitred 0 1 1 0
+obstacle 0 0 0
+obstacle 1 0 0
+obstacle 0 1 0
+segment 1 1 0 1 0 0 0 single
+",
+        );
+
+        assert!(matches!(
+            Snapshot::read(file.as_bytes()),
+            Err(SnapshotError::FullGrid)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_fewer_max_pipes_than_pipes() {
+        let simulation = PipeSimulation::new(&Settings {
+            pipe_count: 3,
+            ..Default::default()
+        });
+        let mut snapshot = Snapshot {
+            simulation: simulation.snapshot(),
+            camera_rotation: 0.0,
+        };
+        snapshot.simulation.rolling = Some(RollingRules {
+            max_pipes: Some(2),
+            max_fill: 0.5,
+        });
+        let mut file = vec![];
+        snapshot.write(&mut file).unwrap();
+
+        assert!(matches!(
+            Snapshot::read(&file[..]),
+            Err(SnapshotError::TooFewMaxPipes(2, 3))
+        ));
+    }
+}
@@ -1,60 +1,359 @@
-use crate::{SPACE_DEPTH, SPACE_HEIGHT, SPACE_WIDTH};
+use crate::{
+    direction::Direction,
+    settings::GridSize,
+    simulation::{PipeId, Segment},
+};
 use cgmath::Vector3;
-use std::mem::size_of;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-const CELL_BITS: usize = size_of::<u64>() * 8;
+/// What a single space is filled with.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Space {
+    Free,
+    /// Part of a pipe, holding the segment placed there.
+    Pipe(Segment),
+    /// Blocked by an obstacle, which pipes must grow around.
+    Obstacle,
+}
 
+/// The grid pipes grow in, keeping track of the segment in every space along
+/// with the pipe it belongs to.
+///
+/// Whether each space is filled is kept in a bitset, which is all growth needs
+/// to know. Segments are only stored for the spaces pipes fill, and indexed by
+/// pipe so a pipe's segments can be found without searching the grid.
 #[derive(Debug, Clone)]
 pub struct Spaces {
-    spaces: Vec<u64>,
+    size: GridSize,
+    /// Whether moving off one side of the grid wraps around to the other.
+    wrap: bool,
+    /// Whether each space is filled, either by a pipe or an obstacle.
+    filled: BitGrid,
+    /// Whether each space is blocked by an obstacle.
+    obstacles: BitGrid,
+    /// How many spaces are blocked by obstacles.
+    blocked: usize,
+    /// The segment in every space filled by a pipe.
+    segments: HashMap<Vector3<usize>, Segment>,
+    /// The locations of each pipe's segments in the order they were placed,
+    /// by pipe from oldest to newest.
+    pipes: BTreeMap<PipeId, Vec<Vector3<usize>>>,
 }
 
-impl Default for Spaces {
-    fn default() -> Self {
+impl Spaces {
+    /// Creates a new set of empty spaces with the given dimensions, optionally
+    /// wrapping around at the edges. Panics if the size isn't valid.
+    pub fn new(size: GridSize, wrap: bool) -> Spaces {
+        assert!(size.is_valid(), "Invalid grid size: {}", size);
+
         Spaces {
-            // round up division
-            spaces: vec![0; (SPACE_WIDTH * SPACE_HEIGHT * SPACE_DEPTH - 1) / CELL_BITS + 1],
+            size,
+            wrap,
+            filled: BitGrid::new(size.volume()),
+            obstacles: BitGrid::new(size.volume()),
+            blocked: 0,
+            segments: HashMap::new(),
+            pipes: BTreeMap::new(),
         }
     }
-}
 
-impl Spaces {
-    /// Clears all the spaces.
-    pub fn clear(&mut self) {
-        for space in self.spaces.iter_mut() {
-            *space = 0;
+    /// Gets the dimensions of these spaces.
+    pub fn size(&self) -> GridSize {
+        self.size
+    }
+
+    /// Gets whether moving off one side of these spaces wraps around to the
+    /// other.
+    pub fn wraps(&self) -> bool {
+        self.wrap
+    }
+
+    /// Gets the space next to a location in a direction, if it doesn't lead
+    /// off the grid.
+    ///
+    /// When wrapping, moves along an axis only one space long have no
+    /// neighbor, since they would wrap right back around to where they
+    /// started.
+    pub fn neighbor(&self, loc: Vector3<usize>, direction: Direction) -> Option<Vector3<usize>> {
+        if self.wrap {
+            let vector = direction.vector();
+            let size = self.size;
+            let thin = [
+                (vector.x, size.width),
+                (vector.y, size.height),
+                (vector.z, size.depth),
+            ]
+            .iter()
+            .any(|&(component, length)| component != 0 && length == 1);
+
+            (!thin).then(|| direction.wrapping_offset(loc, size))
+        } else if direction.is_offset_legal(loc, self.size) {
+            Some(direction.offset(loc))
+        } else {
+            None
         }
     }
 
-    /// Sets a space to occupied.
-    pub fn set(&mut self, x: usize, y: usize, z: usize) {
-        if x >= SPACE_WIDTH || y >= SPACE_HEIGHT || z >= SPACE_DEPTH {
-            panic!("Setting a space out of bounds: ({}, {}, {})", x, y, z);
+    /// Clears all the spaces occupied by pipes, leaving obstacles in place.
+    pub fn clear(&mut self) {
+        self.filled = self.obstacles.clone();
+        self.segments.clear();
+        self.pipes.clear();
+    }
+
+    /// Places a segment in the space at its location, replacing any segment
+    /// already there.
+    pub(crate) fn place(&mut self, segment: Segment) {
+        let location = segment.location;
+        let index = self.index(location.x, location.y, location.z);
+        if self.obstacles.get(index) {
+            panic!("Placed segment on obstacle: {:?}", segment);
         }
 
-        self.spaces[(x + y * SPACE_WIDTH + z * SPACE_WIDTH * SPACE_HEIGHT) / CELL_BITS] |=
-            1 << ((x + y * SPACE_WIDTH + z * SPACE_WIDTH * SPACE_HEIGHT) % CELL_BITS);
+        match self.segments.insert(location, segment) {
+            Some(replaced) if replaced.pipe == segment.pipe => return,
+            Some(replaced) => self.forget_location(replaced.pipe, location),
+            None => self.filled.set(index, true),
+        }
+        self.pipes.entry(segment.pipe).or_default().push(location);
     }
 
-    /// Sets a space to occupied.
-    pub fn set_vec(&mut self, loc: Vector3<usize>) {
-        self.set(loc.x, loc.y, loc.z);
+    /// Removes every segment of a pipe, returning them in the order they were
+    /// placed.
+    pub(crate) fn remove_pipe(&mut self, pipe: PipeId) -> Vec<Segment> {
+        let locations = self.pipes.remove(&pipe).unwrap_or_default();
+
+        locations
+            .into_iter()
+            .map(|location| {
+                let index = self.index(location.x, location.y, location.z);
+                self.filled.set(index, false);
+                self.segments.remove(&location).unwrap()
+            })
+            .collect()
     }
 
-    /// Gets whether a space is occupied.
-    pub fn get(&self, x: usize, y: usize, z: usize) -> bool {
-        if x >= SPACE_WIDTH || y >= SPACE_HEIGHT || z >= SPACE_DEPTH {
-            panic!("Setting a space out of bounds: ({}, {}, {})", x, y, z);
+    /// Blocks a space with an obstacle.
+    pub(crate) fn set_obstacle(&mut self, loc: Vector3<usize>) {
+        let index = self.index(loc.x, loc.y, loc.z);
+        if self.segments.contains_key(&loc) {
+            panic!("Placed obstacle on pipe at {:?}", loc);
         }
 
-        let one = 1 << ((x + y * SPACE_WIDTH + z * SPACE_WIDTH * SPACE_HEIGHT) % CELL_BITS);
+        if !self.obstacles.get(index) {
+            self.blocked += 1;
+        }
+        self.obstacles.set(index, true);
+        self.filled.set(index, true);
+    }
 
-        return self.spaces[(x + y * SPACE_WIDTH + z * SPACE_WIDTH * SPACE_HEIGHT) / CELL_BITS] & one
-            == one;
+    /// Gets whether a space is occupied, either by a pipe or an obstacle.
+    ///
+    /// This only checks the bitset of filled spaces, making it the fast path
+    /// for growth, which only cares whether a space is free.
+    pub fn get(&self, x: usize, y: usize, z: usize) -> bool {
+        self.filled.get(self.index(x, y, z))
     }
 
-    /// Gets whether a space is occupied.
+    /// Gets whether a space is occupied, either by a pipe or an obstacle.
     pub fn get_vec(&self, loc: Vector3<usize>) -> bool {
         self.get(loc.x, loc.y, loc.z)
     }
+
+    /// Gets what a space is filled with.
+    pub fn space(&self, loc: Vector3<usize>) -> Space {
+        let index = self.index(loc.x, loc.y, loc.z);
+
+        match self.segments.get(&loc) {
+            Some(&segment) => Space::Pipe(segment),
+            None if self.obstacles.get(index) => Space::Obstacle,
+            None => Space::Free,
+        }
+    }
+
+    /// Gets the segment in a space, if it is part of a pipe.
+    pub fn segment(&self, loc: Vector3<usize>) -> Option<&Segment> {
+        self.segments.get(&loc)
+    }
+
+    /// Gets every segment in these spaces, by pipe from oldest to newest and
+    /// in the order each pipe's segments were placed.
+    pub fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.pipes
+            .values()
+            .flatten()
+            .map(|location| &self.segments[location])
+    }
+
+    /// Gets every segment belonging to a pipe, in the order they were placed.
+    pub fn pipe_segments(&self, pipe: PipeId) -> impl Iterator<Item = &Segment> {
+        self.pipes
+            .get(&pipe)
+            .into_iter()
+            .flatten()
+            .map(|location| &self.segments[location])
+    }
+
+    /// Gets every pipe with segments in these spaces, from oldest to newest.
+    pub fn pipes(&self) -> impl Iterator<Item = PipeId> + '_ {
+        self.pipes.keys().copied()
+    }
+
+    /// Gets the number of pipes with segments in these spaces.
+    pub fn pipe_count(&self) -> usize {
+        self.pipes.len()
+    }
+
+    /// Gets the locations of every free space.
+    pub fn free_spaces(&self) -> impl Iterator<Item = Vector3<usize>> + '_ {
+        self.locations_of(|index| !self.filled.get(index))
+    }
+
+    /// Gets the locations of every obstacle.
+    pub fn obstacles(&self) -> impl Iterator<Item = Vector3<usize>> + '_ {
+        self.locations_of(|index| self.obstacles.get(index))
+    }
+
+    /// Gets the number of spaces filled by pipes.
+    pub fn occupied_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Gets the fraction of the spaces not blocked by obstacles that are
+    /// filled by pipes, from 0 to 1.
+    pub fn fill_fraction(&self) -> f32 {
+        self.segments.len() as f32 / (self.size.volume() - self.blocked) as f32
+    }
+
+    /// Gets the spaces sharing a face with a location, along with the
+    /// directions they are in.
+    pub fn neighbors(
+        &self,
+        loc: Vector3<usize>,
+    ) -> impl Iterator<Item = (Direction, Vector3<usize>)> + '_ {
+        Direction::axes()
+            .filter_map(move |direction| Some((direction, self.neighbor(loc, direction)?)))
+    }
+
+    /// Gets whether a pipe can move from a location in a direction without
+    /// leaving the grid or running into anything.
+    ///
+    /// Diagonal moves also need every space they pass by to be free, which
+    /// stops diagonal pipes from crossing through each other.
+    pub fn is_move_clear(&self, loc: Vector3<usize>, direction: Direction) -> bool {
+        let Some(target) = self.neighbor(loc, direction) else {
+            return false;
+        };
+
+        // every combination of the axes the move runs along, ending with the
+        // space being moved into
+        (1..8).all(|axes: usize| {
+            let pick = |axis: usize| axes & (1 << axis) != 0;
+            let passed = Vector3::new(
+                if pick(0) { target.x } else { loc.x },
+                if pick(1) { target.y } else { loc.y },
+                if pick(2) { target.z } else { loc.z },
+            );
+
+            // combinations made only of axes the move doesn't run along stay put
+            passed == loc || !self.get_vec(passed)
+        })
+    }
+
+    /// Measures the pocket of free spaces connected to `start`, looking no
+    /// more than `depth` steps away from it.
+    ///
+    /// Returns the number of free spaces in the pocket, or `None` if the
+    /// pocket reaches `depth` steps away and might continue beyond that.
+    pub fn enclosed_volume(&self, start: Vector3<usize>, depth: u32) -> Option<usize> {
+        if self.get_vec(start) {
+            return Some(0);
+        }
+
+        let mut visited = HashSet::new();
+        visited.insert(start);
+        let mut frontier = vec![start];
+
+        for _ in 0..depth {
+            let mut next = vec![];
+
+            for location in frontier {
+                for (_, neighbor) in self.neighbors(location) {
+                    if !self.get_vec(neighbor) && visited.insert(neighbor) {
+                        next.push(neighbor);
+                    }
+                }
+            }
+
+            if next.is_empty() {
+                return Some(visited.len());
+            }
+            frontier = next;
+        }
+
+        None
+    }
+
+    /// Gets the locations of every space whose index matches a predicate.
+    fn locations_of<'a, F: Fn(usize) -> bool + 'a>(
+        &'a self,
+        matches: F,
+    ) -> impl Iterator<Item = Vector3<usize>> + 'a {
+        let size = self.size;
+
+        (0..size.volume())
+            .filter(move |&index| matches(index))
+            .map(move |index| {
+                Vector3::new(
+                    index % size.width,
+                    index / size.width % size.height,
+                    index / (size.width * size.height),
+                )
+            })
+    }
+
+    /// Drops a location from the index of a pipe's segments.
+    fn forget_location(&mut self, pipe: PipeId, location: Vector3<usize>) {
+        if let Some(locations) = self.pipes.get_mut(&pipe) {
+            locations.retain(|&other| other != location);
+            if locations.is_empty() {
+                self.pipes.remove(&pipe);
+            }
+        }
+    }
+
+    /// Converts a location into an index, panicking if it is out of bounds.
+    fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        if x >= self.size.width || y >= self.size.height || z >= self.size.depth {
+            panic!("Accessing a space out of bounds: ({}, {}, {})", x, y, z);
+        }
+
+        x + y * self.size.width + z * self.size.width * self.size.height
+    }
+}
+
+/// A single bit for every space in a grid.
+#[derive(Debug, Clone)]
+struct BitGrid {
+    words: Vec<u64>,
+}
+
+impl BitGrid {
+    fn new(len: usize) -> BitGrid {
+        BitGrid {
+            words: vec![0; len.div_ceil(64)],
+        }
+    }
+
+    fn get(&self, index: usize) -> bool {
+        self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, index: usize, value: bool) {
+        if value {
+            self.words[index / 64] |= 1 << (index % 64);
+        } else {
+            self.words[index / 64] &= !(1 << (index % 64));
+        }
+    }
 }
@@ -0,0 +1,307 @@
+use kpipes_core::{
+    color::{ColorGradient, ColorScheme},
+    obstacles::Obstacles,
+    settings::{ClearTransition, GridSize, JointStyle, Settings},
+};
+use std::{
+    fmt::{Display, Formatter},
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Describes the command line options accepted by KPipes.
+pub const USAGE: &str = "Usage: kpipes-desktop [OPTIONS]
+
+Options:
+  --grid-size <WxHxD>         Dimensions of the space pipes grow in [default: 20x20x20]
+  --wrap                      Let pipes wrap around the edges of the space to the
+                              opposite side
+  --pipes <N>                 Number of pipes growing at the same time [default: 1]
+  --seed <SEED>               Seed for generating pipes [default: random]
+  --straight-weight <WEIGHT>  Weight of going straight relative to each turn [default: 1]
+  --min-straight-run <N>      Segments a pipe must go straight before turning [default: 0]
+  --vertical-weight <WEIGHT>  Multiplier for the weight of moving up or down [default: 1]
+  --lookahead <DEPTH>         Steps pipes look ahead to avoid boxing themselves in,
+                              trading CPU for longer pipes [default: 0]
+  --diagonal                  Let pipes also grow diagonally
+  --teapot-chance <CHANCE>    Chance of a bend being drawn as a teapot [default: 0.005]
+  --joint-style <STYLE>       How pipes turn, one of mitred, ball or mixed (picked per
+                              pipe) [default: mitred]
+  --colors <SCHEME>           How pipes are colored, one of random, palette (random
+                              palette colors), analogous (hues close to the last
+                              pipe's), monochrome or cycle (palette colors in turn)
+                              [default: random]
+  --palette <FILE>            Load the palette used by the palette and cycle schemes
+                              from a file with a hex color on each line
+  --hue <DEGREES>             Hue of the monochrome scheme [default: 210]
+  --gradient <GRADIENT>       How colors change along each pipe, one of none,
+                              hue-drift[:DEGREES] (per segment), fade:COLOR[:LENGTH]
+                              (to a hex color over LENGTH segments) or height[:MIN]
+                              (brighter higher up) [default: none]
+  --clear-transition <STYLE>  How the board animates away when it fills up, one of
+                              instant, fade, shrink or dissolve [default: fade]
+  --rolling                   Never clear the board, retiring the oldest pipes to
+                              make room for new ones instead
+  --max-pipes <N>             Most pipes on the board at once in rolling mode, at
+                              least --pipes, implies --rolling [default: no limit]
+  --max-fill <FRACTION>       Fraction of the space filled before the oldest pipes
+                              are retired, implies --rolling [default: 0.6]
+  --retire-duration <SECS>    Seconds retired pipes take to fade away [default: 1.5]
+  --obstacles <SHAPE>         Obstacles pipes grow around, one of none, sphere, box or
+                              pillars [default: none]
+  --obstacle-mask <FILE>      Load obstacles from a mask file, using its grid size
+  --hide-obstacles            Don't draw obstacles, leaving them to be shown by the
+                              pipes growing around them
+  --record <FILE>             Record the pipes as they grow to a file
+  --replay <FILE>             Replay pipes recorded with --record, ignoring the
+                              options controlling how pipes grow
+  --snapshot <FILE>           Continue from a snapshot in FILE if it exists, and
+                              keep saving the scene to it
+  --snapshot-interval <SECS>  Seconds between saving snapshots [default: 60]
+  -h, --help                  Print this help message";
+
+/// Options parsed from the command line.
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub settings: Settings,
+    /// A palette file to load, if any.
+    pub palette: Option<PathBuf>,
+    /// An obstacle mask file to load, if any.
+    pub obstacle_mask: Option<PathBuf>,
+    /// Where to record the pipes to, if anywhere.
+    pub record: Option<PathBuf>,
+    /// A recording to replay, if any.
+    pub replay: Option<PathBuf>,
+    /// Where to restore the scene from and save it to, if anywhere.
+    pub snapshot: Option<PathBuf>,
+    /// How often to save the scene to the snapshot file.
+    pub snapshot_interval: Duration,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            settings: Default::default(),
+            palette: None,
+            obstacle_mask: None,
+            record: None,
+            replay: None,
+            snapshot: None,
+            snapshot_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Parses KPipes options from command line arguments, not including the
+/// program name.
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Args, ArgsError> {
+    let mut parsed = Args::default();
+    let settings = &mut parsed.settings;
+    let mut args = args.into_iter();
+    let mut hue = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Err(ArgsError::HelpRequested),
+            "--grid-size" => settings.grid_size = parse_value(&arg, &mut args, GridSize::parse)?,
+            "--wrap" => settings.wrap = true,
+            "--pipes" => {
+                settings.pipe_count = parse_value(&arg, &mut args, |value| {
+                    value.parse().ok().filter(|&count| count > 0)
+                })?
+            }
+            "--seed" => {
+                settings.seed = Some(parse_value(&arg, &mut args, |value| value.parse().ok())?)
+            }
+            "--straight-weight" => {
+                settings.growth_rules.straight_weight = parse_value(&arg, &mut args, parse_weight)?
+            }
+            "--min-straight-run" => {
+                settings.growth_rules.min_straight_run =
+                    parse_value(&arg, &mut args, |value| value.parse().ok())?
+            }
+            "--vertical-weight" => {
+                settings.growth_rules.vertical_weight = parse_value(&arg, &mut args, parse_weight)?
+            }
+            "--lookahead" => {
+                settings.growth_rules.lookahead =
+                    parse_value(&arg, &mut args, |value| value.parse().ok())?
+            }
+            "--diagonal" => settings.growth_rules.diagonal = true,
+            "--teapot-chance" => {
+                settings.teapot_chance = parse_value(&arg, &mut args, |value| {
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|chance| (0.0..=1.0).contains(chance))
+                })?
+            }
+            "--joint-style" => {
+                settings.joint_style = parse_value(&arg, &mut args, JointStyle::from_name)?
+            }
+            "--colors" => {
+                settings.color_scheme = parse_value(&arg, &mut args, ColorScheme::from_name)?
+            }
+            "--palette" => {
+                parsed.palette = Some(parse_value(&arg, &mut args, |value| Some(value.into()))?)
+            }
+            "--hue" => {
+                hue = Some(parse_value(&arg, &mut args, |value| {
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|degrees: &f32| degrees.is_finite())
+                        .map(|degrees| degrees.rem_euclid(360.0) / 360.0)
+                })?)
+            }
+            "--gradient" => {
+                settings.color_gradient = parse_value(&arg, &mut args, ColorGradient::parse)?
+            }
+            "--clear-transition" => {
+                settings.clear_transition = parse_value(&arg, &mut args, parse_clear_transition)?
+            }
+            "--rolling" => {
+                settings.rolling.get_or_insert_with(Default::default);
+            }
+            "--max-pipes" => {
+                settings
+                    .rolling
+                    .get_or_insert_with(Default::default)
+                    .max_pipes = Some(parse_value(&arg, &mut args, |value| {
+                    value.parse().ok().filter(|&max| max > 0)
+                })?)
+            }
+            "--max-fill" => {
+                settings
+                    .rolling
+                    .get_or_insert_with(Default::default)
+                    .max_fill = parse_value(&arg, &mut args, |value| {
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|fraction| (0.0..=1.0).contains(fraction))
+                })?
+            }
+            "--retire-duration" => {
+                settings.retire_duration = parse_value(&arg, &mut args, |value| {
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|seconds: &f32| seconds.is_finite() && *seconds >= 0.0)
+                        .map(Duration::from_secs_f32)
+                })?
+            }
+            "--obstacles" => {
+                settings.obstacles = parse_value(&arg, &mut args, Obstacles::from_name)?
+            }
+            "--obstacle-mask" => {
+                parsed.obstacle_mask =
+                    Some(parse_value(&arg, &mut args, |value| Some(value.into()))?)
+            }
+            "--hide-obstacles" => settings.show_obstacles = false,
+            "--record" => {
+                parsed.record = Some(parse_value(&arg, &mut args, |value| Some(value.into()))?)
+            }
+            "--replay" => {
+                parsed.replay = Some(parse_value(&arg, &mut args, |value| Some(value.into()))?)
+            }
+            "--snapshot" => {
+                parsed.snapshot = Some(parse_value(&arg, &mut args, |value| Some(value.into()))?)
+            }
+            "--snapshot-interval" => {
+                parsed.snapshot_interval = parse_value(&arg, &mut args, |value| {
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|seconds: &f32| seconds.is_finite() && *seconds > 0.0)
+                        .map(Duration::from_secs_f32)
+                })?
+            }
+            _ => return Err(ArgsError::UnknownArgument(arg)),
+        }
+    }
+
+    if let (ColorScheme::Monochrome(scheme_hue), Some(hue)) = (&mut settings.color_scheme, hue) {
+        *scheme_hue = hue;
+    }
+
+    // growing pipes are never retired, so they all have to fit
+    if let Some(max_pipes) = settings.rolling.and_then(|rules| rules.max_pipes) {
+        if max_pipes < settings.pipe_count {
+            return Err(ArgsError::TooFewMaxPipes(max_pipes, settings.pipe_count));
+        }
+    }
+
+    // replays and recordings always start from an empty board
+    if parsed.snapshot.is_some() {
+        if parsed.replay.is_some() {
+            return Err(ArgsError::Conflict("--snapshot", "--replay"));
+        }
+        if parsed.record.is_some() {
+            return Err(ArgsError::Conflict("--snapshot", "--record"));
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Takes the value following an option and parses it.
+fn parse_value<I, T, F>(arg: &str, args: &mut I, parse: F) -> Result<T, ArgsError>
+where
+    I: Iterator<Item = String>,
+    F: FnOnce(&str) -> Option<T>,
+{
+    let value = args
+        .next()
+        .ok_or_else(|| ArgsError::MissingValue(arg.to_string()))?;
+    parse(&value).ok_or_else(|| ArgsError::InvalidValue(arg.to_string(), value))
+}
+
+/// Parses a non-negative weight.
+fn parse_weight(value: &str) -> Option<f32> {
+    value
+        .parse()
+        .ok()
+        .filter(|weight: &f32| weight.is_finite() && *weight >= 0.0)
+}
+
+/// Parses the name of a clear transition.
+fn parse_clear_transition(value: &str) -> Option<ClearTransition> {
+    match value {
+        "instant" => Some(ClearTransition::Instant),
+        "fade" => Some(ClearTransition::Fade),
+        "shrink" => Some(ClearTransition::Shrink),
+        "dissolve" => Some(ClearTransition::Dissolve),
+        _ => None,
+    }
+}
+
+/// Error potentially returned when parsing command line arguments.
+#[derive(Debug, Clone)]
+pub enum ArgsError {
+    HelpRequested,
+    UnknownArgument(String),
+    MissingValue(String),
+    InvalidValue(String, String),
+    Conflict(&'static str, &'static str),
+    TooFewMaxPipes(usize, usize),
+}
+
+impl Display for ArgsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgsError::HelpRequested => write!(f, "Help requested"),
+            ArgsError::UnknownArgument(arg) => write!(f, "Unknown argument: {}", arg),
+            ArgsError::MissingValue(arg) => write!(f, "Missing value for {}", arg),
+            ArgsError::InvalidValue(arg, value) => {
+                write!(f, "Invalid value for {}: {}", arg, value)
+            }
+            ArgsError::Conflict(a, b) => write!(f, "{} can't be used with {}", a, b),
+            ArgsError::TooFewMaxPipes(max_pipes, pipe_count) => write!(
+                f,
+                "--max-pipes {} is less than the {} pipes growing at once",
+                max_pipes, pipe_count
+            ),
+        }
+    }
+}
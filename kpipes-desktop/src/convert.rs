@@ -53,6 +53,7 @@ impl FromVirtualKeyCode for KeyCode {
         match code {
             VirtualKeyCode::Escape => KeyCode::Escape,
             VirtualKeyCode::C => KeyCode::C,
+            VirtualKeyCode::R => KeyCode::R,
             _ => KeyCode::Other,
         }
     }
@@ -62,10 +63,7 @@ impl FromKeyboardInput for KeyboardEvent {
     fn from_keyboard_input(input: KeyboardInput) -> Self {
         KeyboardEvent {
             state: KeyState::from_element_state(input.state),
-            virtual_keycode: match input.virtual_keycode {
-                Some(code) => Some(KeyCode::from_virtual_key_code(code)),
-                None => None,
-            },
+            virtual_keycode: input.virtual_keycode.map(KeyCode::from_virtual_key_code),
         }
     }
 }
@@ -3,7 +3,7 @@ use futures::executor::block_on;
 use kpipes_core::messages::{FlowControl, FlowEvent, FrameSize};
 use std::time::{Duration, SystemTime};
 use wgpu::{
-    Backends, CommandBuffer, Device, DeviceDescriptor, Instance, InstanceDescriptor,
+    Backends, CommandBuffer, Device, DeviceDescriptor, Features, Instance, InstanceDescriptor,
     PowerPreference, PresentMode, Queue, RequestAdapterOptions, SurfaceConfiguration, SurfaceError,
     TextureFormat, TextureUsages, TextureView,
 };
@@ -15,14 +15,19 @@ use winit::{
     window::{Fullscreen, WindowBuilder},
 };
 
+type ModelInit<Model> = Box<dyn Fn(&Device, &Queue, FrameSize, TextureFormat) -> Model>;
+type EventCallback<Model> = Box<dyn Fn(&mut Model, &Device, FlowEvent) -> FlowControl>;
+type UpdateCallback<Model> = Box<dyn Fn(&mut Model, Duration) -> FlowControl>;
+type RenderCallback<Model> =
+    Box<dyn Fn(&mut Model, &Device, &Queue, &mut Vec<CommandBuffer>, &TextureView, Duration)>;
+
 /// Used to manage an application's control flow as well as integration with the
 /// window manager.
 pub struct Flow<Model: 'static> {
-    model_init: Box<dyn Fn(&Device, &Queue, FrameSize, TextureFormat) -> Model>,
-    event_callback: Option<Box<dyn Fn(&mut Model, &Device, FlowEvent) -> FlowControl>>,
-    update_callback: Option<Box<dyn Fn(&mut Model, &Device, Duration) -> FlowControl>>,
-    render_callback:
-        Option<Box<dyn Fn(&mut Model, &Device, &mut Vec<CommandBuffer>, &TextureView, Duration)>>,
+    model_init: ModelInit<Model>,
+    event_callback: Option<EventCallback<Model>>,
+    update_callback: Option<UpdateCallback<Model>>,
+    render_callback: Option<RenderCallback<Model>>,
 
     /// The window's title.
     pub title: String,
@@ -62,7 +67,7 @@ impl<Model: 'static> Flow<Model> {
     }
 
     /// Sets the Flow's update callback.
-    pub fn update<F: Fn(&mut Model, &Device, Duration) -> FlowControl + 'static>(
+    pub fn update<F: Fn(&mut Model, Duration) -> FlowControl + 'static>(
         &mut self,
         update_callback: F,
     ) {
@@ -71,7 +76,7 @@ impl<Model: 'static> Flow<Model> {
 
     /// Sets the Flow's render callback.
     pub fn render<
-        F: Fn(&mut Model, &Device, &mut Vec<CommandBuffer>, &TextureView, Duration) + 'static,
+        F: Fn(&mut Model, &Device, &Queue, &mut Vec<CommandBuffer>, &TextureView, Duration) + 'static,
     >(
         &mut self,
         render_callback: F,
@@ -118,7 +123,10 @@ impl<Model: 'static> Flow<Model> {
             &DeviceDescriptor {
                 label: Some("device"),
                 limits: Default::default(),
-                features: Default::default(),
+                // lets the renderer draw instance groups from its indirect
+                // buffer, all at once where possible
+                features: adapter.features()
+                    & (Features::MULTI_DRAW_INDIRECT | Features::INDIRECT_FIRST_INSTANCE),
             },
             None,
         ))
@@ -180,7 +188,7 @@ impl<Model: 'static> Flow<Model> {
                 previous_update = now;
 
                 if let Some(update_callback) = &self.update_callback {
-                    if update_callback(&mut model, &device, delta) == FlowControl::Exit {
+                    if update_callback(&mut model, delta) == FlowControl::Exit {
                         *control = ControlFlow::Exit;
                     }
                 }
@@ -199,7 +207,14 @@ impl<Model: 'static> Flow<Model> {
                         Ok(frame) => {
                             let view = frame.texture.create_view(&Default::default());
 
-                            render_callback(&mut model, &device, &mut commands, &view, delta);
+                            render_callback(
+                                &mut model,
+                                &device,
+                                &queue,
+                                &mut commands,
+                                &view,
+                                delta,
+                            );
 
                             queue.submit(commands.drain(..));
 
@@ -219,6 +234,7 @@ impl<Model: 'static> Flow<Model> {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum FlowStartError {
     OsError(OsError),
 }
@@ -1,18 +1,181 @@
-use crate::flow::Flow;
-use kpipes_core::KPipes;
+use crate::{args::ArgsError, flow::Flow};
+use kpipes_core::{
+    color::Palette,
+    messages::FlowControl,
+    obstacles::{ObstacleMask, Obstacles},
+    recording::Recording,
+    snapshot::Snapshot,
+    KPipes,
+};
+use log::{error, info};
+use std::{
+    cell::Cell,
+    env, fs,
+    fs::File,
+    io,
+    io::{BufReader, BufWriter},
+    path::Path,
+    process,
+    rc::Rc,
+    time::Duration,
+};
 
+mod args;
 mod convert;
 mod flow;
 
 fn main() {
     env_logger::init();
 
-    let mut flow = Flow::new(KPipes::init);
-    flow.event(KPipes::event);
-    flow.update(KPipes::update);
+    let args = match args::parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(ArgsError::HelpRequested) => {
+            println!("{}", args::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, args::USAGE);
+            process::exit(1);
+        }
+    };
+
+    let mut settings = args.settings;
+
+    if let Some(path) = &args.palette {
+        let palette = File::open(path)
+            .map_err(|e| e.into())
+            .and_then(|file| Palette::read(BufReader::new(file)));
+
+        match palette {
+            Ok(palette) => settings.color_scheme = settings.color_scheme.with_palette(palette),
+            Err(e) => {
+                eprintln!("Unable to load palette from {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &args.obstacle_mask {
+        let mask = File::open(path)
+            .map_err(|e| e.into())
+            .and_then(|file| ObstacleMask::read(BufReader::new(file)));
+
+        match mask {
+            Ok(mask) => settings.obstacles = Obstacles::Mask(mask),
+            Err(e) => {
+                eprintln!("Unable to load obstacles from {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &args.replay {
+        let recording = File::open(path)
+            .map_err(|e| e.into())
+            .and_then(|file| Recording::read(BufReader::new(file)));
+
+        match recording {
+            Ok(recording) => settings.replay = Some(recording),
+            Err(e) => {
+                eprintln!("Unable to replay {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &args.snapshot {
+        match File::open(path) {
+            Ok(file) => match Snapshot::read(BufReader::new(file)) {
+                Ok(snapshot) => {
+                    info!("Continuing from snapshot {}", path.display());
+                    settings.snapshot = Some(snapshot);
+                }
+                Err(e) => {
+                    eprintln!("Unable to restore {}: {}", path.display(), e);
+                    process::exit(1);
+                }
+            },
+            // there is nothing to continue from the first time a snapshot is used
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                eprintln!("Unable to restore {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // open the recording file early so problems are reported before the window
+    // opens
+    let record_file = args.record.as_ref().map(|path| {
+        File::create(path).unwrap_or_else(|e| {
+            eprintln!("Unable to record to {}: {}", path.display(), e);
+            process::exit(1);
+        })
+    });
+
+    let mut flow = Flow::new(move |device, queue, window_size, color_format| {
+        let mut kpipes = KPipes::init(device, queue, window_size, color_format, settings.clone());
+        // shown without any logging enabled, so a nice run can always be
+        // repeated with --seed
+        eprintln!("Pipe seed: {}", kpipes.seed());
+
+        if let Some(file) = &record_file {
+            let file = file.try_clone().unwrap();
+            kpipes.record(BufWriter::new(file)).unwrap();
+        }
+
+        kpipes
+    });
+
+    match args.snapshot {
+        Some(path) => {
+            let path = Rc::new(path);
+            let since_saved = Rc::new(Cell::new(Duration::ZERO));
+            let interval = args.snapshot_interval;
+
+            let event_path = path.clone();
+            flow.event(move |kpipes, device, event| {
+                let control = kpipes.event(device, event);
+                if control == FlowControl::Exit {
+                    save_snapshot(kpipes, &event_path);
+                }
+                control
+            });
+            flow.update(move |kpipes, delta| {
+                let control = kpipes.update(delta);
+
+                since_saved.set(since_saved.get() + delta);
+                if since_saved.get() >= interval || control == FlowControl::Exit {
+                    since_saved.set(Duration::ZERO);
+                    save_snapshot(kpipes, &path);
+                }
+
+                control
+            });
+        }
+        None => {
+            flow.event(KPipes::event);
+            flow.update(KPipes::update);
+        }
+    }
     flow.render(KPipes::render);
     flow.title = "KPipes".to_string();
     flow.fullscreen = true;
 
     flow.start().unwrap();
 }
+
+/// Saves a snapshot of the scene, replacing the previous snapshot only once the
+/// new one has been completely written.
+fn save_snapshot(kpipes: &KPipes, path: &Path) {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    let result = File::create(&temp_path)
+        .and_then(|file| kpipes.snapshot().write(BufWriter::new(file)))
+        .and_then(|_| fs::rename(&temp_path, path));
+
+    if let Err(e) = result {
+        error!("Unable to save snapshot to {}: {}", path.display(), e);
+    }
+}